@@ -1,19 +1,27 @@
 use crate::matrix::matrix::Mat;
 use anyhow::anyhow;
-use clap::Parser;
-use gstfastestdet::fastestdet::common::{nms_handle, paint_targets, ImageModel};
-use gstfastestdet::fastestdet::fastest_det::FastestDet;
-use gstfastestdet::fastestdet::yolo_fastest::YoloFastest;
+use clap::{Args as ClapArgs, CommandFactory, FromArgMatches, Parser, Subcommand};
+use gstfastestdet::fastestdet::common::{
+    nms_handle, paint_targets, ClassFilter, Classes, PixelFormat,
+};
+use gstfastestdet::fastestdet::config::{load_model, Architecture};
+use gstfastestdet::fastestdet::int8::calibrate;
+use gstfastestdet::fastestdet::runtime::RuntimeOptions;
+use gstfastestdet::fastestdet::style::PaintStyle;
+use gstfastestdet::fastestdet::tile::{detect_tiled, TileConfig};
+use gstfastestdet::fastestdet::{PaintMode, TileMerge};
 use image::buffer::ConvertBuffer;
 use image::{Rgb32FImage, RgbImage};
 use protobuf::Message;
-use serde_derive::Deserialize;
 mod matrix;
 
 #[derive(Debug, Clone, Copy)]
 enum Backend {
     YoloFastest,
     FastestDet,
+    Yolov5,
+    Yolov8,
+    NanodetPlus,
 }
 
 impl std::str::FromStr for Backend {
@@ -22,20 +30,39 @@ impl std::str::FromStr for Backend {
         match s {
             "yolo_fastest" => Ok(Backend::YoloFastest),
             "fastest_det" => Ok(Backend::FastestDet),
+            "yolov5" => Ok(Backend::Yolov5),
+            "yolov8" => Ok(Backend::Yolov8),
+            "nanodet_plus" => Ok(Backend::NanodetPlus),
             _ => Err(anyhow!("unknown backend")),
         }
     }
 }
 
+impl Backend {
+    fn architecture(&self) -> Architecture {
+        match self {
+            Backend::YoloFastest => Architecture::YoloFastest,
+            Backend::FastestDet => Architecture::FastestDet,
+            Backend::Yolov5 => Architecture::Yolov5,
+            Backend::Yolov8 => Architecture::Yolov8,
+            Backend::NanodetPlus => Architecture::NanodetPlus,
+        }
+    }
+}
+
 impl std::fmt::Display for Backend {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Backend::YoloFastest => write!(f, "yolo_fastest"),
             Backend::FastestDet => write!(f, "fastest_det"),
+            Backend::Yolov5 => write!(f, "yolov5"),
+            Backend::Yolov8 => write!(f, "yolov8"),
+            Backend::NanodetPlus => write!(f, "nanodet_plus"),
         }
     }
 }
 
+// detection, the default command, see `Commands` for the others
 #[derive(Parser, Debug)]
 #[command(author, about, long_about = None)]
 struct Args {
@@ -48,7 +75,7 @@ struct Args {
     /// bin
     #[arg(long)]
     model_path: String,
-    /// toml
+    /// toml, the class list and optionally a `[model]` table
     #[arg(long)]
     classes_path: String,
     /// nms
@@ -57,15 +84,90 @@ struct Args {
     /// output
     #[arg(short, long)]
     output: String,
+    /// fastest_det, yolo_fastest, yolov5, yolov8 or nanodet_plus,
+    /// overridden by `architecture` in the `[model]` table
     #[arg(short, long, default_value_t = Backend::FastestDet)]
     backend: Backend,
     #[arg(short, long, default_value_t = 0.3)]
     threshold: f32,
+    /// class names or indices to report, comma separated, every class if not given
+    #[arg(long, value_delimiter = ',')]
+    include_classes: Vec<String>,
+    /// class names or indices not to report, comma separated
+    #[arg(long, value_delimiter = ',')]
+    exclude_classes: Vec<String>,
+    /// toml, the colors, thickness, corners, label template and font of the boxes
+    #[arg(long)]
+    style: Option<String>,
+    /// boxes, blur, pixelate or solid-fill, for the classes without a mode in the style
+    #[arg(long, default_value = "boxes", value_parser = parse_paint_mode)]
+    paint_mode: PaintMode,
+    /// detect on overlapping tiles, for the small objects of large pictures
+    #[arg(long)]
+    tile: bool,
+    /// tile width in pixels of the picture
+    #[arg(long, default_value_t = 640)]
+    tile_width: u32,
+    /// tile height in pixels of the picture
+    #[arg(long, default_value_t = 640)]
+    tile_height: u32,
+    /// part of a tile shared with the next one, in [0, 1)
+    #[arg(long, default_value_t = 0.2)]
+    tile_overlap: f32,
+    /// also detect on the whole picture, for the objects larger than a tile
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    tile_full_frame: bool,
+    /// nms or fusion, how the boxes of an object found on several tiles are merged
+    #[arg(long, default_value = "nms", value_parser = parse_tile_merge)]
+    tile_merge: TileMerge,
+    /// ncnn threads, 0 for ncnn's default
+    #[arg(long, default_value_t = 0)]
+    num_threads: u32,
+    /// run on the GPU, ncnn should be built with Vulkan
+    #[arg(long)]
+    vulkan: bool,
 }
 
-#[derive(Deserialize, Debug)]
-struct Classes {
-    pub classes: Vec<String>,
+fn parse_paint_mode(s: &str) -> Result<PaintMode, String> {
+    match s {
+        "boxes" => Ok(PaintMode::Boxes),
+        "blur" => Ok(PaintMode::Blur),
+        "pixelate" => Ok(PaintMode::Pixelate),
+        "solid-fill" => Ok(PaintMode::SolidFill),
+        _ => Err("expected boxes, blur, pixelate or solid-fill".to_string()),
+    }
+}
+
+fn parse_tile_merge(s: &str) -> Result<TileMerge, String> {
+    match s {
+        "nms" => Ok(TileMerge::Nms),
+        "fusion" => Ok(TileMerge::Fusion),
+        _ => Err("expected nms or fusion".to_string()),
+    }
+}
+
+impl Args {
+    fn runtime(&self) -> RuntimeOptions {
+        RuntimeOptions {
+            num_threads: self.num_threads,
+            vulkan: self.vulkan,
+        }
+    }
+
+    /// `None` without `--tile`
+    fn tiling(&self) -> anyhow::Result<Option<TileConfig>> {
+        if !self.tile {
+            return Ok(None);
+        }
+        let tiling = TileConfig {
+            size: (self.tile_width, self.tile_height),
+            overlap: self.tile_overlap,
+            full_frame: self.tile_full_frame,
+            merge: self.tile_merge,
+        };
+        tiling.check()?;
+        Ok(Some(tiling))
+    }
 }
 
 fn mat_to_rgbimg(mat: &ncnn_rs::Mat) -> anyhow::Result<Rgb32FImage> {
@@ -126,33 +228,112 @@ fn img_resize(img: &RgbImage, size: (i32, i32)) -> anyhow::Result<Rgb32FImage> {
     Ok(resized)
 }
 
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Write the int8 calibration table of a fp32 model for ncnn2int8, with
+    /// the inputs prepared like the detectors do
+    Calibrate(CalibrateArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+struct CalibrateArgs {
+    /// The directory of the calibration pictures, a few hundred like the ones to detect on
+    #[arg(short, long)]
+    images: String,
+    /// param, fp32
+    #[arg(long)]
+    param_path: String,
+    /// bin, fp32
+    #[arg(long)]
+    model_path: String,
+    /// toml, the class list and optionally a `[model]` table
+    #[arg(long)]
+    classes_path: String,
+    /// see the detection arguments
+    #[arg(short, long, default_value_t = Backend::FastestDet)]
+    backend: Backend,
+    /// the table to write
+    #[arg(short, long)]
+    output: String,
+    /// ncnn threads, 0 for ncnn's default
+    #[arg(long, default_value_t = 0)]
+    num_threads: u32,
+}
+
+fn calibrate_main(args: CalibrateArgs) -> Result<(), anyhow::Error> {
+    let mut images = vec![];
+    for entry in std::fs::read_dir(&args.images)? {
+        let path = entry?.path();
+        let is_image = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| ["jpg", "jpeg", "png", "bmp"].contains(&e.to_lowercase().as_str()))
+            .unwrap_or(false);
+        if is_image {
+            images.push(path);
+        }
+    }
+    images.sort();
+    println!("calibrating with {} pictures", images.len());
+    let classes = Classes::from_file(&args.classes_path)?;
+    let config = classes.model_config(args.backend.architecture())?;
+    let runtime = RuntimeOptions {
+        num_threads: args.num_threads,
+        ..RuntimeOptions::default()
+    };
+    let table = calibrate(&args.param_path, &args.model_path, &config, &runtime, &images)?;
+    table.write(std::io::BufWriter::new(std::fs::File::create(&args.output)?))?;
+    println!("wrote {}", args.output);
+    Ok(())
+}
+
 pub fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
-    let mut img = image::open(args.input)?;
+    // the detection arguments are only required without a subcommand
+    let matches = Commands::augment_subcommands(Args::command())
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .get_matches();
+    if matches.subcommand_name().is_some() {
+        return match Commands::from_arg_matches(&matches)? {
+            Commands::Calibrate(args) => calibrate_main(args),
+        };
+    }
+    let args = Args::from_arg_matches(&matches)?;
+    let mut img = image::open(&args.input)?;
     let (w, h) = (img.width() as i32, img.height() as i32);
-    let content = std::fs::read_to_string(args.classes_path)?;
-    let classes = toml::from_str::<Classes>(&content)?.classes;
+    let classes = Classes::from_file(&args.classes_path)?;
+    let class_thresholds = classes.class_thresholds()?;
+    let class_filter =
+        ClassFilter::new(&args.include_classes, &args.exclude_classes, &classes.classes)?;
+    let style = match args.style {
+        Some(ref path) => PaintStyle::from_file(path)?,
+        None => PaintStyle::default(),
+    };
+    let config = classes.model_config(args.backend.architecture())?;
+    let classes = classes.classes;
     let labels = classes.clone();
     let rgb_img = img.as_mut_rgb8().ok_or(anyhow::anyhow!("not rgb8"))?;
-    let targets = match args.backend {
-        Backend::YoloFastest => {
-            let mut det = YoloFastest::new(args.param_path, args.model_path, classes)?;
-            let img_mat = det.preprocess(rgb_img)?;
-            let targets = det.detect(&img_mat, (w, h), args.threshold)?;
-            let nms_targets = nms_handle(&targets, args.nms_threshold);
-            nms_targets
+    let runtime = args.runtime();
+    let mut det = load_model(&args.param_path, &args.model_path, &config, &runtime, classes)?;
+    let mut targets = match args.tiling()? {
+        Some(tiling) => {
+            let samples = rgb_img.as_flat_samples();
+            let thresholds = (args.threshold, args.nms_threshold);
+            let format = PixelFormat::Rgb;
+            detect_tiled(det.as_mut(), &samples, format, &tiling, &class_thresholds, thresholds)?
         }
-        Backend::FastestDet => {
-            let mut det = FastestDet::new(args.param_path, args.model_path, (352, 352), classes)?;
-            let img_mat = det.preprocess(rgb_img)?;
-            let targets = det.detect(&img_mat, (w, h), args.threshold)?;
-            let nms_targets = nms_handle(&targets, args.nms_threshold);
-            nms_targets
+        None => {
+            let img_mat = det.preprocess(&rgb_img.as_flat_samples(), PixelFormat::Rgb)?;
+            let threshold = class_thresholds.min(args.threshold);
+            let mut targets = det.detect(&img_mat, (w, h), threshold)?;
+            class_thresholds.retain(&mut targets, args.threshold);
+            nms_handle(&targets, args.nms_threshold)
         }
     };
+    class_filter.retain(&mut targets, &labels);
     println!("nms_targets: {}", targets.len());
     dbg!(&targets);
-    paint_targets(rgb_img, &targets, &labels)?;
+    paint_targets(rgb_img, &targets, &labels, &style, args.paint_mode)?;
     rgb_img.save(args.output)?;
     Ok(())
 }
@@ -0,0 +1,607 @@
+// Zone occupancy and line crossing counts on top of the targets
+use super::common::TargetBox;
+use super::track::TrackerConfig;
+use anyhow::{bail, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// The point of a box which is tested against the zones and lines
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Anchor {
+    Center,
+    /// where people and vehicles touch the ground
+    #[default]
+    BottomCenter,
+}
+
+impl Anchor {
+    fn point(&self, target: &TargetBox) -> (f32, f32) {
+        let x = (target.x1 + target.x2) as f32 / 2.0;
+        match self {
+            Anchor::Center => (x, (target.y1 + target.y2) as f32 / 2.0),
+            Anchor::BottomCenter => (x, target.y2 as f32),
+        }
+    }
+}
+
+/// A polygon, in pixels of the frame
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Zone {
+    pub name: String,
+    pub points: Vec<[f32; 2]>,
+}
+
+impl Zone {
+    /// even-odd rule
+    fn contains(&self, (x, y): (f32, f32)) -> bool {
+        let mut inside = false;
+        let mut j = self.points.len() - 1;
+        for (i, [xi, yi]) in self.points.iter().enumerate() {
+            let [xj, yj] = self.points[j];
+            if (*yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+                inside = !inside;
+            }
+            j = i;
+        }
+        inside
+    }
+}
+
+/// A tripwire from `from` to `to`, in pixels of the frame
+///
+/// Looking from `from` to `to`, a target crossing from the left to the right
+/// side goes `LeftToRight`. The y axis of the frame points down.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Line {
+    pub name: String,
+    pub from: [f32; 2],
+    pub to: [f32; 2],
+}
+
+/// > 0 on the right of `a -> b` in frame coordinates, < 0 on the left
+fn side(a: [f32; 2], b: [f32; 2], (x, y): (f32, f32)) -> f32 {
+    (b[0] - a[0]) * (y - a[1]) - (b[1] - a[1]) * (x - a[0])
+}
+
+impl Line {
+    fn crossing(&self, before: (f32, f32), after: (f32, f32)) -> Option<Direction> {
+        let (side_before, side_after) = (
+            side(self.from, self.to, before),
+            side(self.from, self.to, after),
+        );
+        if side_before == 0.0 || side_after == 0.0 || (side_before > 0.0) == (side_after > 0.0) {
+            return None;
+        }
+        // the move should go between the ends of the line, not around them
+        let (a, b) = ([before.0, before.1], [after.0, after.1]);
+        let (from, to) = ((self.from[0], self.from[1]), (self.to[0], self.to[1]));
+        if (side(a, b, from) > 0.0) == (side(a, b, to) > 0.0) {
+            return None;
+        }
+        if side_before < 0.0 {
+            Some(Direction::LeftToRight)
+        } else {
+            Some(Direction::RightToLeft)
+        }
+    }
+}
+
+/// The zones and lines, e.g.
+///
+/// ```toml
+/// anchor = "bottom-center"
+///
+/// [[zone]]
+/// name = "door"
+/// points = [[100, 400], [300, 400], [300, 600], [100, 600]]
+///
+/// [[line]]
+/// name = "entrance"
+/// from = [0, 360]
+/// to = [640, 360]
+/// ```
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AnalyticsConfig {
+    #[serde(default)]
+    pub anchor: Anchor,
+    #[serde(default, rename = "zone")]
+    pub zones: Vec<Zone>,
+    #[serde(default, rename = "line")]
+    pub lines: Vec<Line>,
+}
+
+impl AnalyticsConfig {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read analytics file `{}`", path))?;
+        Self::parse(&text).with_context(|| format!("malformed analytics file `{}`", path))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: AnalyticsConfig = toml::from_str(text)?;
+        for zone in config.zones.iter() {
+            if zone.points.len() < 3 {
+                bail!("zone `{}` needs at least 3 points", zone.name);
+            }
+        }
+        for line in config.lines.iter() {
+            if line.from == line.to {
+                bail!("line `{}` has the same start and end", line.name);
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    LeftToRight,
+    RightToLeft,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum AnalyticsEvent {
+    Enter {
+        zone: String,
+        track_id: u64,
+        label: String,
+    },
+    Exit {
+        zone: String,
+        track_id: u64,
+        label: String,
+    },
+    Cross {
+        line: String,
+        direction: Direction,
+        track_id: u64,
+        label: String,
+    },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ZoneOccupancy {
+    pub zone: String,
+    /// targets inside, by label
+    pub counts: BTreeMap<String, u32>,
+    /// since the previous update
+    #[serde(skip)]
+    pub changed: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct Crossings {
+    pub left_to_right: u64,
+    pub right_to_left: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LineCounts {
+    pub line: String,
+    /// since the start of the stream, by label
+    pub counts: BTreeMap<String, Crossings>,
+}
+
+/// The analytics of one detected frame
+#[derive(Serialize, Debug, Clone)]
+pub struct FrameAnalytics {
+    pub zones: Vec<ZoneOccupancy>,
+    pub lines: Vec<LineCounts>,
+    pub events: Vec<AnalyticsEvent>,
+}
+
+struct TrackState {
+    point: (f32, f32),
+    label: String,
+    /// by zone
+    inside: Vec<bool>,
+    last_update: u64,
+}
+
+/// Counts the targets in each zone, and with track ids the targets entering
+/// and leaving the zones and crossing the lines
+///
+/// Only occupancy can be told from the boxes alone, the events need the
+/// `track_id` of the targets to follow them from one update to the next.
+///
+/// A track first seen inside a zone enters it, and a track ending inside a
+/// zone exits it, so each `Exit` has its `Enter`. A track is ended by the
+/// tracker, or after `max_misses` updates without it, the same as the tracker
+/// when its events aren't passed in.
+pub struct Analytics {
+    pub config: AnalyticsConfig,
+    /// should be the tracker's
+    pub max_misses: u32,
+    tracks: HashMap<u64, TrackState>,
+    occupancy: Vec<BTreeMap<String, u32>>,
+    crossings: Vec<BTreeMap<String, Crossings>>,
+    updates: u64,
+}
+
+impl Analytics {
+    pub fn new(config: AnalyticsConfig) -> Self {
+        let occupancy = vec![BTreeMap::new(); config.zones.len()];
+        let crossings = vec![BTreeMap::new(); config.lines.len()];
+        Analytics {
+            config,
+            max_misses: TrackerConfig::default().max_misses,
+            tracks: HashMap::new(),
+            occupancy,
+            crossings,
+            updates: 0,
+        }
+    }
+
+    /// Start over, for a new stream
+    pub fn reset(&mut self) {
+        let max_misses = self.max_misses;
+        *self = Analytics::new(self.config.clone());
+        self.max_misses = max_misses;
+    }
+
+    /// The `Exit`s of the zones `track` was inside
+    fn exits(&self, id: u64, track: &TrackState, events: &mut Vec<AnalyticsEvent>) {
+        for (zone, _) in self
+            .config
+            .zones
+            .iter()
+            .zip(track.inside.iter())
+            .filter(|(_, i)| **i)
+        {
+            events.push(AnalyticsEvent::Exit {
+                zone: zone.name.clone(),
+                track_id: id,
+                label: track.label.clone(),
+            });
+        }
+    }
+
+    /// `targets` of a detected frame, `labels` indexed by their class
+    ///
+    /// `ended` are the targets of the tracks the tracker ended since the
+    /// previous update, see `TrackEventKind::End`.
+    pub fn update(
+        &mut self,
+        targets: &[TargetBox],
+        ended: &[TargetBox],
+        labels: &[String],
+    ) -> FrameAnalytics {
+        self.updates += 1;
+        let config = &self.config;
+        let label_of = |target: &TargetBox| {
+            labels
+                .get(target.class as usize)
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string())
+        };
+        let mut occupancy = vec![BTreeMap::new(); config.zones.len()];
+        let mut events = vec![];
+        for id in ended.iter().filter_map(|target| target.track_id) {
+            if let Some(track) = self.tracks.remove(&id) {
+                self.exits(id, &track, &mut events);
+            }
+        }
+        for target in targets.iter() {
+            let point = config.anchor.point(target);
+            let label = label_of(target);
+            let inside: Vec<bool> = config
+                .zones
+                .iter()
+                .map(|zone| zone.contains(point))
+                .collect();
+            for (counts, _) in occupancy.iter_mut().zip(inside.iter()).filter(|(_, i)| **i) {
+                *counts.entry(label.clone()).or_insert(0) += 1;
+            }
+            let id = match target.track_id {
+                Some(id) => id,
+                None => continue,
+            };
+            let previous = self.tracks.get(&id);
+            let was_inside = match previous {
+                Some(previous) => previous.inside.clone(),
+                None => vec![false; inside.len()],
+            };
+            for (zone, (was, is)) in config
+                .zones
+                .iter()
+                .zip(was_inside.iter().zip(inside.iter()))
+            {
+                let (zone, track_id, label) = (zone.name.clone(), id, label.clone());
+                match (was, is) {
+                    (false, true) => events.push(AnalyticsEvent::Enter {
+                        zone,
+                        track_id,
+                        label,
+                    }),
+                    (true, false) => events.push(AnalyticsEvent::Exit {
+                        zone,
+                        track_id,
+                        label,
+                    }),
+                    _ => {}
+                }
+            }
+            if let Some(previous) = previous {
+                for (i, line) in config.lines.iter().enumerate() {
+                    if let Some(direction) = line.crossing(previous.point, point) {
+                        let counts = self.crossings[i].entry(label.clone()).or_default();
+                        match direction {
+                            Direction::LeftToRight => counts.left_to_right += 1,
+                            Direction::RightToLeft => counts.right_to_left += 1,
+                        }
+                        events.push(AnalyticsEvent::Cross {
+                            line: line.name.clone(),
+                            direction,
+                            track_id: id,
+                            label: label.clone(),
+                        });
+                    }
+                }
+            }
+            self.tracks.insert(
+                id,
+                TrackState {
+                    point,
+                    label,
+                    inside,
+                    last_update: self.updates,
+                },
+            );
+        }
+        let (updates, max_misses) = (self.updates, self.max_misses as u64);
+        let lost: Vec<u64> = self
+            .tracks
+            .iter()
+            .filter(|(_, track)| updates - track.last_update > max_misses)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in lost {
+            let track = self.tracks.remove(&id).unwrap();
+            self.exits(id, &track, &mut events);
+        }
+        let zones = config
+            .zones
+            .iter()
+            .zip(occupancy.into_iter().zip(self.occupancy.iter_mut()))
+            .map(|(zone, (counts, previous))| {
+                let changed = counts != *previous;
+                *previous = counts.clone();
+                ZoneOccupancy {
+                    zone: zone.name.clone(),
+                    counts,
+                    changed,
+                }
+            })
+            .collect();
+        let lines = config
+            .lines
+            .iter()
+            .zip(self.crossings.iter())
+            .map(|(line, counts)| LineCounts {
+                line: line.name.clone(),
+                counts: counts.clone(),
+            })
+            .collect();
+        FrameAnalytics {
+            zones,
+            lines,
+            events,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+[[zone]]
+name = 'door'
+points = [[0, 0], [100, 0], [100, 100], [0, 100]]
+
+[[line]]
+name = 'entrance'
+from = [0, 200]
+to = [300, 200]
+";
+
+    fn square() -> Zone {
+        Zone {
+            name: "square".to_string(),
+            points: vec![[0.0, 0.0], [100.0, 0.0], [100.0, 100.0], [0.0, 100.0]],
+        }
+    }
+
+    fn line() -> Line {
+        Line {
+            name: "line".to_string(),
+            from: [0.0, 200.0],
+            to: [300.0, 200.0],
+        }
+    }
+
+    /// A box with its bottom center at `(x, y)`
+    fn target(x: i32, y: i32, track_id: Option<u64>) -> TargetBox {
+        TargetBox {
+            x1: x - 10,
+            y1: y - 20,
+            x2: x + 10,
+            y2: y,
+            score: 0.9,
+            class: 0,
+            track_id,
+        }
+    }
+
+    fn labels() -> Vec<String> {
+        vec!["person".to_string()]
+    }
+
+    #[test]
+    fn zone_contains() {
+        let zone = square();
+        assert!(zone.contains((50.0, 50.0)));
+        assert!(zone.contains((1.0, 99.0)));
+        assert!(!zone.contains((150.0, 50.0)));
+        assert!(!zone.contains((50.0, -1.0)));
+        // concave, the notch at the top isn't inside
+        let zone = Zone {
+            name: "u".to_string(),
+            points: vec![
+                [0.0, 0.0],
+                [30.0, 0.0],
+                [30.0, 70.0],
+                [70.0, 70.0],
+                [70.0, 0.0],
+                [100.0, 0.0],
+                [100.0, 100.0],
+                [0.0, 100.0],
+            ],
+        };
+        assert!(zone.contains((10.0, 10.0)));
+        assert!(zone.contains((50.0, 90.0)));
+        assert!(!zone.contains((50.0, 10.0)));
+    }
+
+    #[test]
+    fn crossing_direction() {
+        let line = line();
+        // the right of `from -> to` is below it, the y axis pointing down
+        assert_eq!(
+            line.crossing((100.0, 150.0), (100.0, 250.0)),
+            Some(Direction::LeftToRight)
+        );
+        assert_eq!(
+            line.crossing((100.0, 250.0), (120.0, 150.0)),
+            Some(Direction::RightToLeft)
+        );
+        // the other way around when the line is reversed
+        let reversed = Line {
+            from: line.to,
+            to: line.from,
+            ..line.clone()
+        };
+        assert_eq!(
+            reversed.crossing((100.0, 150.0), (100.0, 250.0)),
+            Some(Direction::RightToLeft)
+        );
+        // staying on a side, or stopping on the line, isn't a crossing
+        assert_eq!(line.crossing((100.0, 150.0), (200.0, 190.0)), None);
+        assert_eq!(line.crossing((100.0, 150.0), (100.0, 200.0)), None);
+    }
+
+    #[test]
+    fn crossing_between_the_ends() {
+        let line = line();
+        assert_eq!(line.crossing((-10.0, 150.0), (-10.0, 250.0)), None);
+        assert_eq!(line.crossing((310.0, 150.0), (350.0, 250.0)), None);
+        assert_eq!(
+            line.crossing((290.0, 150.0), (290.0, 250.0)),
+            Some(Direction::LeftToRight)
+        );
+        // passing around the end of the line, although the sides change
+        assert_eq!(line.crossing((400.0, 150.0), (250.0, 400.0)), None);
+    }
+
+    fn is_enter(event: &AnalyticsEvent, id: u64) -> bool {
+        matches!(event, AnalyticsEvent::Enter { track_id, .. } if *track_id == id)
+    }
+
+    fn is_exit(event: &AnalyticsEvent, id: u64) -> bool {
+        matches!(event, AnalyticsEvent::Exit { track_id, .. } if *track_id == id)
+    }
+
+    #[test]
+    fn occupancy_without_tracks() {
+        let mut analytics = Analytics::new(AnalyticsConfig::parse(CONFIG).unwrap());
+        let frame = analytics.update(
+            &[
+                target(50, 50, None),
+                target(60, 60, None),
+                target(150, 150, None),
+            ],
+            &[],
+            &labels(),
+        );
+        assert_eq!(frame.zones[0].counts["person"], 2);
+        assert!(frame.zones[0].changed);
+        assert!(frame.events.is_empty());
+        let frame = analytics.update(
+            &[target(50, 50, None), target(60, 60, None)],
+            &[],
+            &labels(),
+        );
+        assert!(!frame.zones[0].changed);
+    }
+
+    #[test]
+    fn enter_and_exit() {
+        let mut analytics = Analytics::new(AnalyticsConfig::parse(CONFIG).unwrap());
+        let frame = analytics.update(&[target(50, 50, Some(1))], &[], &labels());
+        assert_eq!(frame.events.len(), 1);
+        assert!(is_enter(&frame.events[0], 1));
+        // staying inside
+        let frame = analytics.update(&[target(60, 50, Some(1))], &[], &labels());
+        assert!(frame.events.is_empty());
+        let frame = analytics.update(&[target(150, 150, Some(1))], &[], &labels());
+        assert_eq!(frame.events.len(), 1);
+        assert!(is_exit(&frame.events[0], 1));
+        let frame = analytics.update(&[target(150, 250, Some(1))], &[], &labels());
+        assert!(matches!(
+            frame.events[..],
+            [AnalyticsEvent::Cross {
+                direction: Direction::LeftToRight,
+                track_id: 1,
+                ..
+            }]
+        ));
+        assert_eq!(frame.lines[0].counts["person"].left_to_right, 1);
+    }
+
+    #[test]
+    fn ended_tracks_exit() {
+        let mut analytics = Analytics::new(AnalyticsConfig::parse(CONFIG).unwrap());
+        analytics.update(&[target(50, 50, Some(2))], &[], &labels());
+        let frame = analytics.update(&[], &[target(50, 50, Some(2))], &labels());
+        assert_eq!(frame.events.len(), 1);
+        assert!(is_exit(&frame.events[0], 2));
+        // a track ended outside of the zones has nothing to exit
+        analytics.update(&[target(150, 150, Some(3))], &[], &labels());
+        let frame = analytics.update(&[], &[target(150, 150, Some(3))], &labels());
+        assert!(frame.events.is_empty());
+        // nor a track already ended
+        let frame = analytics.update(&[], &[target(50, 50, Some(2))], &labels());
+        assert!(frame.events.is_empty());
+    }
+
+    #[test]
+    fn lost_tracks_exit() {
+        let mut analytics = Analytics::new(AnalyticsConfig::parse(CONFIG).unwrap());
+        analytics.max_misses = 2;
+        analytics.update(&[target(50, 50, Some(4))], &[], &labels());
+        for _ in 0..2 {
+            assert!(analytics.update(&[], &[], &labels()).events.is_empty());
+        }
+        let frame = analytics.update(&[], &[], &labels());
+        assert_eq!(frame.events.len(), 1);
+        assert!(is_exit(&frame.events[0], 4));
+        // seen again, it enters again
+        let frame = analytics.update(&[target(50, 50, Some(4))], &[], &labels());
+        assert!(is_enter(&frame.events[0], 4));
+    }
+
+    #[test]
+    fn config_checks() {
+        assert!(AnalyticsConfig::parse("[[zone]]\nname = 'z'\npoints = [[0, 0], [1, 1]]").is_err());
+        assert!(
+            AnalyticsConfig::parse("[[line]]\nname = 'l'\nfrom = [1, 1]\nto = [1, 1]").is_err()
+        );
+        assert!(AnalyticsConfig::parse("[[zone]]\nname = 'z'\ncolor = 'red'").is_err());
+    }
+}
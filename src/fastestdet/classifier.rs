@@ -0,0 +1,240 @@
+// Second stage classification of the targets, e.g. the color of the cars
+use super::common::{resize_pixels, Classification, ImageClassifier, PixelFormat};
+use super::config::ChannelOrder;
+use super::runtime::RuntimeOptions;
+use anyhow::{bail, Context, Result};
+use image::flat::FlatSamples;
+use ncnn_rs::{Allocator as NcnnAllocator, Mat, Net};
+use serde_derive::Deserialize;
+
+/// Everything a classifier needs besides the weights and the class list
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassifierConfig {
+    /// (width, height)
+    pub input_size: (i32, i32),
+    pub input_blob: String,
+    pub output_blob: String,
+    pub mean_vals: Vec<f32>,
+    pub norm_vals: Vec<f32>,
+    pub channel_order: ChannelOrder,
+    /// whether `output_blob` gives logits rather than probabilities
+    pub softmax: bool,
+}
+
+impl Default for ClassifierConfig {
+    // the ImageNet classifiers converted by ncnn's tools
+    fn default() -> Self {
+        ClassifierConfig {
+            input_size: (224, 224),
+            input_blob: "data".to_string(),
+            output_blob: "prob".to_string(),
+            mean_vals: vec![0.0, 0.0, 0.0],
+            norm_vals: vec![1.0 / 255.0, 1.0 / 255.0, 1.0 / 255.0],
+            channel_order: ChannelOrder::Rgb,
+            softmax: false,
+        }
+    }
+}
+
+/// The `[model]` table of the classifier labels TOML, see `ClassifierLabels`
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ClassifierSection {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub input: Option<String>,
+    pub output: Option<String>,
+    pub mean: Option<Vec<f32>>,
+    pub norm: Option<Vec<f32>>,
+    pub channel_order: Option<ChannelOrder>,
+    pub softmax: Option<bool>,
+}
+
+impl ClassifierSection {
+    pub fn resolve(&self) -> Result<ClassifierConfig> {
+        let default = ClassifierConfig::default();
+        let config = ClassifierConfig {
+            input_size: (
+                self.width.unwrap_or(default.input_size.0),
+                self.height.unwrap_or(default.input_size.1),
+            ),
+            input_blob: self.input.clone().unwrap_or(default.input_blob),
+            output_blob: self.output.clone().unwrap_or(default.output_blob),
+            mean_vals: self.mean.clone().unwrap_or(default.mean_vals),
+            norm_vals: self.norm.clone().unwrap_or(default.norm_vals),
+            channel_order: self.channel_order.unwrap_or(default.channel_order),
+            softmax: self.softmax.unwrap_or(default.softmax),
+        };
+        let (width, height) = config.input_size;
+        if width <= 0 || height <= 0 {
+            bail!("invalid input size {}x{}", width, height);
+        }
+        if config.mean_vals.len() != 3 || config.norm_vals.len() != 3 {
+            bail!("`mean` and `norm` should have 3 values, one per channel");
+        }
+        Ok(config)
+    }
+}
+
+/// The labels TOML of a classifier, e.g.
+///
+/// ```toml
+/// classes = ["black", "blue", "red", "silver", "white"]
+///
+/// [model]
+/// width = 224
+/// height = 224
+/// input = "data"
+/// output = "prob"
+/// mean = [0.0, 0.0, 0.0]
+/// norm = [0.00392157, 0.00392157, 0.00392157]
+/// channel-order = "rgb"
+/// # when `output` gives logits
+/// softmax = true
+/// ```
+#[derive(Deserialize, Debug)]
+pub struct ClassifierLabels {
+    pub classes: Vec<String>,
+    #[serde(default)]
+    pub model: ClassifierSection,
+}
+
+impl ClassifierLabels {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read labels file `{}`", path))?;
+        let labels = toml::from_str::<ClassifierLabels>(&text)
+            .with_context(|| format!("malformed labels file `{}`", path))?;
+        if labels.classes.is_empty() {
+            bail!("labels file `{}` has no classes", path);
+        }
+        Ok(labels)
+    }
+}
+
+pub struct NcnnClassifier {
+    alloc: NcnnAllocator,
+    net: Net,
+    classes: Vec<String>,
+    config: ClassifierConfig,
+}
+
+// same as the detectors
+unsafe impl Send for NcnnClassifier {}
+unsafe impl Sync for NcnnClassifier {}
+
+impl ImageClassifier for NcnnClassifier {
+    /// the whole `img` is stretched to the input, crop it first
+    fn preprocess(&self, img: &FlatSamples<&[u8]>, format: PixelFormat) -> Result<Mat> {
+        let target = self.config.channel_order.to_ncnn();
+        let mut input = resize_pixels(
+            img,
+            format,
+            target,
+            self.config.input_size,
+            Some(&self.alloc),
+        )?;
+        input.substract_mean_normalize(&self.config.mean_vals, &self.config.norm_vals);
+        Ok(input)
+    }
+
+    fn classify(&mut self, input: &Mat) -> Result<Vec<f32>> {
+        let mut ex = self.net.create_extractor();
+        if let Err(e) = ex.input(&self.config.input_blob, input) {
+            bail!("ex.input error: {}", e);
+        };
+        let mut output = Mat::new();
+        if let Err(e) = ex.extract(&self.config.output_blob, &mut output) {
+            bail!("ex.extract error: {}", e);
+        };
+        let output = output.as_slice::<f32>();
+        let class_num = self.classes.len();
+        if output.len() < class_num {
+            bail!(
+                "output blob `{}` has {} values for {} classes",
+                self.config.output_blob,
+                output.len(),
+                class_num
+            );
+        }
+        let mut scores = output[..class_num].to_vec();
+        if self.config.softmax {
+            softmax(&mut scores);
+        }
+        Ok(scores)
+    }
+
+    fn labels(&self) -> &Vec<String> {
+        &self.classes
+    }
+}
+
+impl NcnnClassifier {
+    pub fn new<P>(
+        param_path: P,
+        model_path: P,
+        config: ClassifierConfig,
+        runtime: &RuntimeOptions,
+        classes: Vec<String>,
+    ) -> Result<Self>
+    where
+        P: AsRef<str>,
+    {
+        let mut classifier = NcnnClassifier {
+            alloc: unsafe { NcnnAllocator::new() },
+            net: runtime.net(),
+            classes,
+            config,
+        };
+        classifier
+            .net
+            .load_param(param_path.as_ref())
+            .with_context(|| format!("failed to load param file `{}`", param_path.as_ref()))?;
+        classifier
+            .net
+            .load_model(model_path.as_ref())
+            .with_context(|| format!("failed to load model file `{}`", model_path.as_ref()))?;
+        Ok(classifier)
+    }
+}
+
+/// Load the classifier described by the labels TOML at `labels_path`
+pub fn load_classifier<P: AsRef<str>>(
+    param_path: P,
+    model_path: P,
+    labels_path: &str,
+    runtime: &RuntimeOptions,
+) -> Result<Box<dyn ImageClassifier>> {
+    let labels = ClassifierLabels::from_file(labels_path)?;
+    let config = labels
+        .model
+        .resolve()
+        .with_context(|| format!("invalid `[model]` in `{}`", labels_path))?;
+    let classifier = NcnnClassifier::new(param_path, model_path, config, runtime, labels.classes)?;
+    Ok(Box::new(classifier))
+}
+
+/// The highest of `scores`, if any
+pub fn best(scores: &[f32]) -> Option<Classification> {
+    scores
+        .iter()
+        .enumerate()
+        .filter(|(_, score)| !score.is_nan())
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(class, score)| Classification {
+            class: class as i32,
+            score: *score,
+        })
+}
+
+fn softmax(scores: &mut [f32]) {
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for score in scores.iter_mut() {
+        *score = (*score - max).exp();
+        sum += *score;
+    }
+    for score in scores.iter_mut() {
+        *score /= sum;
+    }
+}
@@ -0,0 +1,392 @@
+use gst::glib;
+use super::super::classifier::{best, load_classifier};
+use super::super::common::{parse_class_list, Classification, ImageClassifier, PixelFormat};
+use super::super::frame;
+use super::super::runtime::RuntimeOptions;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+use gst::{debug, info};
+use gst_base::subclass::prelude::*;
+use gst_video::subclass::prelude::*;
+use gst_video::VideoRegionOfInterestMeta;
+use image::flat::FlatSamples;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "fastestclassifyrs",
+        gst::DebugColorFlags::empty(),
+        Some("Rust second stage classifier Element"),
+    )
+});
+
+const DEFAULT_MODEL_PATH: &'static str = "classifier.bin";
+const DEFAULT_PARAM_PATH: &'static str = "classifier.param";
+const DEFAULT_LABELS_PATH: &'static str = "labels.toml";
+const DEFAULT_SCORE_THRESHOLD: f32 = 0.0;
+
+pub struct Settings {
+    model_path: String,
+    param_path: String,
+    /// the classes and the `[model]` table, see `ClassifierLabels`
+    labels_path: String,
+    /// the roi types (detection labels) to classify, every one when empty
+    target_classes: Vec<String>,
+    score_threshold: f32,
+    /// ncnn options, applied on the next load
+    runtime: RuntimeOptions,
+    /// loaded when going to READY, dropped back in NULL
+    classifier: Option<Box<dyn ImageClassifier>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            model_path: DEFAULT_MODEL_PATH.to_string(),
+            param_path: DEFAULT_PARAM_PATH.to_string(),
+            labels_path: DEFAULT_LABELS_PATH.to_string(),
+            target_classes: vec![],
+            score_threshold: DEFAULT_SCORE_THRESHOLD,
+            runtime: RuntimeOptions::default(),
+            classifier: None,
+        }
+    }
+}
+
+impl Settings {
+    fn is_target(&self, roi_type: &str) -> bool {
+        self.target_classes.is_empty() || self.target_classes.iter().any(|c| c == roi_type)
+    }
+}
+
+#[derive(Default)]
+pub struct GstFastestClassify {
+    settings: Mutex<Settings>,
+}
+
+impl GstFastestClassify {
+    fn load_classifier(&self, settings: &mut Settings) -> Result<(), gst::ErrorMessage> {
+        let loaded = load_classifier(
+            &settings.param_path,
+            &settings.model_path,
+            &settings.labels_path,
+            &settings.runtime,
+        );
+        match loaded {
+            Ok(classifier) => {
+                info!(CAT, "classifier loaded, {} classes", classifier.labels().len());
+                settings.classifier = Some(classifier);
+                Ok(())
+            }
+            Err(e) => {
+                let message = format!("{:#}", e);
+                gst::error!(CAT, "Failed to create classifier: {}", message);
+                let is_io = e.root_cause().downcast_ref::<std::io::Error>().is_some();
+                if is_io {
+                    Err(gst::error_msg!(gst::ResourceError::OpenRead, ["{}", message]))
+                } else {
+                    Err(gst::error_msg!(gst::LibraryError::Init, ["{}", message]))
+                }
+            }
+        }
+    }
+
+    fn classify(
+        classifier: &mut dyn ImageClassifier,
+        img: &FlatSamples<&[u8]>,
+        format: PixelFormat,
+    ) -> Result<Option<Classification>, anyhow::Error> {
+        let input = classifier.preprocess(img, format)?;
+        let scores = classifier.classify(&input)?;
+        Ok(best(&scores))
+    }
+
+    /// Classify the regions of interest of `frame` whose type is one of
+    /// `target-classes`, and add a `classification` param to their metas
+    ///
+    /// The param has the `label`, `class-id` and `score` of the best class, and
+    /// the name of this element as `classifier` to tell several stages apart.
+    fn transform_impl(
+        &self,
+        frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let mut settings = self.settings.lock().unwrap();
+        let settings = &mut *settings;
+        // by their position among the roi metas, `fastestdetrs` leaves all the
+        // ids at 0
+        let rois: Vec<(usize, (u32, u32, u32, u32))> = frame
+            .buffer()
+            .iter_meta::<VideoRegionOfInterestMeta>()
+            .enumerate()
+            .filter(|(_, meta)| settings.is_target(meta.roi_type()))
+            .map(|(i, meta)| (i, meta.rect()))
+            .collect();
+        let classifier = match settings.classifier {
+            Some(ref mut classifier) if !rois.is_empty() => classifier,
+            _ => return Ok(gst::FlowSuccess::Ok),
+        };
+        let (cols, rows) = (frame.width(), frame.height());
+        let mut results = vec![];
+        {
+            let (data, format, stride) = frame::input_samples(frame).map_err(|e| {
+                gst::error!(CAT, "classify error:{}", e);
+                gst::FlowError::Error
+            })?;
+            let channels = format.channels() as usize;
+            for (i, (x, y, w, h)) in rois {
+                // the metas may come from anywhere, keep them in the frame
+                let (x, y) = (x.min(cols), y.min(rows));
+                let (w, h) = (w.min(cols - x), h.min(rows - y));
+                if w == 0 || h == 0 {
+                    continue;
+                }
+                let start = y as usize * stride + x as usize * channels;
+                let img = frame::plane_samples(&data[start..], format, w, h, stride);
+                let result = Self::classify(classifier.as_mut(), &img, format).map_err(|e| {
+                    gst::error!(CAT, "classify error:{}", e);
+                    gst::FlowError::Error
+                })?;
+                match result {
+                    Some(c) if c.score >= settings.score_threshold => results.push((i, c)),
+                    _ => {}
+                }
+            }
+        }
+        if results.is_empty() {
+            return Ok(gst::FlowSuccess::Ok);
+        }
+        let name = self.obj().name();
+        let labels = classifier.labels();
+        let metas = frame
+            .buffer_mut()
+            .iter_meta_mut::<VideoRegionOfInterestMeta>()
+            .enumerate();
+        let mut results = results.into_iter().peekable();
+        for (i, mut meta) in metas {
+            let c = match results.peek() {
+                Some((at, c)) if *at == i => *c,
+                Some(_) => continue,
+                None => break,
+            };
+            results.next();
+            let label = match labels.get(c.class as usize) {
+                Some(label) => label.as_str(),
+                None => "unknown",
+            };
+            debug!(CAT, "{} classified as {} {:.2}", meta.roi_type(), label, c.score);
+            let param = gst::Structure::builder("classification")
+                .field("classifier", name.as_str())
+                .field("label", label)
+                .field("class-id", c.class)
+                .field("score", c.score as f64)
+                .build();
+            meta.add_param(param);
+        }
+        Ok(gst::FlowSuccess::Ok)
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for GstFastestClassify {
+    const NAME: &'static str = "FastestClassifyRs";
+    type Type = super::GstFastestClassify;
+    type ParentType = gst_video::VideoFilter;
+}
+
+impl ObjectImpl for GstFastestClassify {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecString::builder("model-path")
+                    .nick("Model")
+                    .blurb("Classifier path which should be ended with `.bin`. Applied when going to READY")
+                    .default_value(Some(DEFAULT_MODEL_PATH))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("param-path")
+                    .nick("Param")
+                    .blurb("Param path which should be ended with `.param`. Applied when going to READY")
+                    .default_value(Some(DEFAULT_PARAM_PATH))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("labels-path")
+                    .nick("Labels")
+                    .blurb("Labels path which should be ended with `.toml`, the class list and optionally a `[model]` table describing the network. Applied when going to READY")
+                    .default_value(Some(DEFAULT_LABELS_PATH))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("target-classes")
+                    .nick("Target classes")
+                    .blurb("Comma separated roi types, i.e. the detection labels, to classify, every region when empty")
+                    .default_value(Some(""))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecFloat::builder("score-threshold")
+                    .nick("Score threshold")
+                    .blurb("Classifications with a lower score are not attached")
+                    .minimum(0.0)
+                    .maximum(1.0)
+                    .default_value(DEFAULT_SCORE_THRESHOLD)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("num-threads")
+                    .nick("Threads")
+                    .blurb("Threads used by ncnn, 0 for ncnn's default. Applied when going to READY")
+                    .default_value(0)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecBoolean::builder("vulkan")
+                    .nick("Vulkan")
+                    .blurb("Run on the GPU, needs ncnn built with Vulkan. Applied when going to READY")
+                    .default_value(false)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+            ]
+        });
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        let mut settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "model-path" => {
+                settings.model_path = value.get::<String>().unwrap().trim().to_string();
+                info!(CAT, "Set model path to {}", settings.model_path);
+            }
+            "param-path" => {
+                settings.param_path = value.get::<String>().unwrap().trim().to_string();
+                info!(CAT, "Set param path to {}", settings.param_path);
+            }
+            "labels-path" => {
+                settings.labels_path = value.get::<String>().unwrap().trim().to_string();
+                info!(CAT, "Set labels path to {}", settings.labels_path);
+            }
+            "target-classes" => {
+                let list: Option<String> = value.get().unwrap();
+                settings.target_classes = parse_class_list(&list.unwrap_or_default());
+                info!(CAT, "Set target classes to {:?}", settings.target_classes);
+            }
+            "score-threshold" => {
+                settings.score_threshold = value.get().unwrap();
+                info!(CAT, "Set score threshold to {}", settings.score_threshold);
+            }
+            "num-threads" => {
+                settings.runtime.num_threads = value.get().unwrap();
+                info!(CAT, "Set num threads to {}", settings.runtime.num_threads);
+            }
+            "vulkan" => {
+                settings.runtime.vulkan = value.get().unwrap();
+                info!(CAT, "Set vulkan to {}", settings.runtime.vulkan);
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        let settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "model-path" => settings.model_path.to_value(),
+            "param-path" => settings.param_path.to_value(),
+            "labels-path" => settings.labels_path.to_value(),
+            "target-classes" => settings.target_classes.join(",").to_value(),
+            "score-threshold" => settings.score_threshold.to_value(),
+            "num-threads" => settings.runtime.num_threads.to_value(),
+            "vulkan" => settings.runtime.vulkan.to_value(),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+impl GstObjectImpl for GstFastestClassify {}
+
+impl ElementImpl for GstFastestClassify {
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        match transition {
+            gst::StateChange::NullToReady => {
+                let mut settings = self.settings.lock().unwrap();
+                let result = self.load_classifier(&mut settings);
+                drop(settings);
+                if let Err(err) = result {
+                    self.post_error_message(err);
+                    return Err(gst::StateChangeError);
+                }
+            }
+            gst::StateChange::ReadyToNull => {
+                // the paths may change before the next READY
+                self.settings.lock().unwrap().classifier = None;
+            }
+            _ => {}
+        }
+        self.parent_change_state(transition)
+    }
+
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "FastestDet classifier Rust",
+                "Filter/Analyzer/Video",
+                "Run a ncnn classifier on the regions of interest found upstream and attach the results to them",
+                "Crosstyan <crosstyan@outlook.com>",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    // the same formats as `fastestdetrs`, see `frame.rs`
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let formats = gst::List::new(frame::SUPPORTED_FORMATS.iter().map(|f| f.to_str()));
+            let caps = gst::Caps::builder("video/x-raw")
+                .field("format", formats)
+                .field("width", gst::IntRange::new(0, i32::MAX))
+                .field("height", gst::IntRange::new(0, i32::MAX))
+                .field(
+                    "framerate",
+                    gst::FractionRange::new(
+                        gst::Fraction::new(0, 1),
+                        gst::Fraction::new(i32::MAX, 1),
+                    ),
+                )
+                .build();
+            let src_pad_template = gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+            let sink_pad_template = gst::PadTemplate::new(
+                "sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+            vec![src_pad_template, sink_pad_template]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+}
+
+impl BaseTransformImpl for GstFastestClassify {
+    // the frames are only read, but the metas need a writable buffer
+    const MODE: gst_base::subclass::BaseTransformMode =
+        gst_base::subclass::BaseTransformMode::AlwaysInPlace;
+    const PASSTHROUGH_ON_SAME_CAPS: bool = false;
+    const TRANSFORM_IP_ON_PASSTHROUGH: bool = false;
+}
+
+impl VideoFilterImpl for GstFastestClassify {
+    fn transform_frame_ip(
+        &self,
+        frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        self.transform_impl(frame)
+    }
+}
@@ -0,0 +1,20 @@
+use gst::glib;
+use gst::prelude::*;
+pub mod imp;
+
+// Runs an `ImageClassifier` on the regions of interest attached upstream,
+// usually by `fastestdetrs`, and adds its result to their metas.
+
+glib::wrapper! {
+    pub struct GstFastestClassify(ObjectSubclass<imp::GstFastestClassify>) @extends gst_base::BaseTransform, gst::Element, gst::Object;
+}
+
+// "fastestclassifyrs", the second stage after "fastestdetrs"
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(
+        Some(plugin),
+        "fastestclassifyrs",
+        gst::Rank::None,
+        GstFastestClassify::static_type(),
+    )
+}
@@ -1,12 +1,17 @@
-use image::{ImageBuffer, Rgb};
-use ncnn_rs::{Mat};
-use anyhow::{Result};
-use once_cell::sync::Lazy;
-use rusttype::{Font, Scale};
+use image::flat::FlatSamples;
+use image::{ImageBuffer, Luma, LumaA, Pixel, Rgb, Rgba};
+use imageproc::drawing::Canvas;
+use ncnn_rs::{Allocator as NcnnAllocator, Mat, MatPixelType};
+use super::config::{Architecture, ModelConfig, ModelSection};
+use super::redact;
+use super::style::{Corners, PaintStyle};
+use super::PaintMode;
+use anyhow::{Context, Result};
+use imageproc::rect::Rect;
+use rusttype::Scale;
 use serde_derive::{Deserialize, Serialize};
-use std::ops::{Deref, DerefMut, Index};
-
-static FONT: Lazy<&[u8]> = Lazy::new(|| include_bytes!("DejaVuSans.ttf"));
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
 
 /// trait aliases are experimental
 ///
@@ -15,6 +20,313 @@ static FONT: Lazy<&[u8]> = Lazy::new(|| include_bytes!("DejaVuSans.ttf"));
 /// ```
 pub type RgbBuffer<T> = ImageBuffer<Rgb<u8>, T>;
 
+/// the defaults of `StyleConfig`
+pub const BOX_COLOR: [u8; 3] = [0, 255, 2];
+pub const TEXT_COLOR: [u8; 3] = [242, 255, 128];
+
+/// Layout of the samples passed to `ImageModel::preprocess` and `paint_samples`
+///
+/// `Rgba`/`Bgra` are also used for `RGBx`/`BGRx` since the padding byte is
+/// just ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+    Rgba,
+    Bgra,
+    Gray,
+}
+
+impl PixelFormat {
+    pub fn channels(&self) -> u8 {
+        match self {
+            PixelFormat::Rgb | PixelFormat::Bgr => 3,
+            PixelFormat::Rgba | PixelFormat::Bgra => 4,
+            PixelFormat::Gray => 1,
+        }
+    }
+
+    fn to_ncnn(self) -> MatPixelType {
+        match self {
+            PixelFormat::Rgb => MatPixelType::RGB,
+            PixelFormat::Bgr => MatPixelType::BGR,
+            PixelFormat::Rgba => MatPixelType::RGBA,
+            PixelFormat::Bgra => MatPixelType::BGRA,
+            PixelFormat::Gray => MatPixelType::GRAY,
+        }
+    }
+
+    /// ncnn pixel type which converts `self` into `target`
+    ///
+    /// ncnn has no `PIXEL_RGB2RGB` and the like, so the plain type is used when
+    /// no conversion is needed.
+    pub fn convert_to(&self, target: &MatPixelType) -> i32 {
+        let source = self.to_ncnn();
+        if source.to_int() == target.to_int() {
+            source.to_int()
+        } else {
+            source.convert(target)
+        }
+    }
+
+    /// the samples of a pixel of `color`, given as RGB
+    pub fn pixel(&self, [r, g, b]: [u8; 3]) -> Vec<u8> {
+        match self {
+            PixelFormat::Rgb => vec![r, g, b],
+            PixelFormat::Bgr => vec![b, g, r],
+            PixelFormat::Rgba => vec![r, g, b, 255],
+            PixelFormat::Bgra => vec![b, g, r, 255],
+            PixelFormat::Gray => {
+                vec![(0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32).round() as u8]
+            }
+        }
+    }
+}
+
+/// Resize `img` to `target_size` and convert it from `format` to `target`
+///
+/// The row stride of `img` is passed to ncnn as is.
+pub fn resize_pixels(
+    img: &FlatSamples<&[u8]>,
+    format: PixelFormat,
+    target: MatPixelType,
+    target_size: (i32, i32),
+    alloc: Option<&NcnnAllocator>,
+) -> Result<Mat> {
+    let (_, width, height) = img.bounds();
+    let img_size = (width as i32, height as i32);
+    let (_, _, height_stride) = img.strides_cwh();
+    let input = Mat::from_pixels_resize(
+        img.samples,
+        format.convert_to(&target),
+        img_size,
+        height_stride as i32,
+        target_size,
+        alloc,
+    )?;
+    Ok(input)
+}
+
+/// Where the frame lands when it's letterboxed to the model input
+///
+/// The frame is padded to the aspect ratio of the model input, centered, so
+/// that `from_pixels_resize` scales both axes by the same factor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Letterbox {
+    /// size of the padded frame
+    pub canvas: (i32, i32),
+    /// position of the frame on the canvas
+    pub offset: (i32, i32),
+}
+
+impl Letterbox {
+    pub fn new(img_size: (i32, i32), input_size: (i32, i32)) -> Self {
+        let (w, h) = (img_size.0 as i64, img_size.1 as i64);
+        let (iw, ih) = (input_size.0.max(1) as i64, input_size.1.max(1) as i64);
+        let (cw, ch) = if w * ih >= h * iw {
+            // wider than the input, pad top and bottom
+            (w, (w * ih + iw - 1) / iw)
+        } else {
+            ((h * iw + ih - 1) / ih, h)
+        };
+        Letterbox {
+            canvas: (cw as i32, ch as i32),
+            offset: (((cw - w) / 2) as i32, ((ch - h) / 2) as i32),
+        }
+    }
+
+    /// move targets detected on the canvas back to the frame
+    pub fn unmap(&self, targets: &mut [TargetBox]) {
+        let (ox, oy) = self.offset;
+        for target in targets.iter_mut() {
+            target.x1 -= ox;
+            target.x2 -= ox;
+            target.y1 -= oy;
+            target.y2 -= oy;
+        }
+    }
+}
+
+/// Like `resize_pixels` but keeps the aspect ratio, padding with `pad_color` (RGB)
+///
+/// Targets detected on the result should be mapped back with `Letterbox::unmap`.
+pub fn letterbox_pixels(
+    img: &FlatSamples<&[u8]>,
+    format: PixelFormat,
+    target: MatPixelType,
+    target_size: (i32, i32),
+    pad_color: [u8; 3],
+    alloc: Option<&NcnnAllocator>,
+) -> Result<Mat> {
+    let (_, width, height) = img.bounds();
+    let img_size = (width as i32, height as i32);
+    let letterbox = Letterbox::new(img_size, target_size);
+    if letterbox.canvas == img_size {
+        return resize_pixels(img, format, target, target_size, alloc);
+    }
+    let channels = format.channels() as usize;
+    let row_len = width as usize * channels;
+    let canvas_row_len = letterbox.canvas.0 as usize * channels;
+    let (ox, oy) = (letterbox.offset.0 as usize, letterbox.offset.1 as usize);
+    let pixel = format.pixel(pad_color);
+    let mut canvas: Vec<u8> = pixel
+        .iter()
+        .copied()
+        .cycle()
+        .take(canvas_row_len * letterbox.canvas.1 as usize)
+        .collect();
+    let (_, _, height_stride) = img.strides_cwh();
+    for y in 0..height as usize {
+        let src = &img.samples[y * height_stride..y * height_stride + row_len];
+        let start = (y + oy) * canvas_row_len + ox * channels;
+        canvas[start..start + row_len].copy_from_slice(src);
+    }
+    let input = Mat::from_pixels_resize(
+        &canvas,
+        format.convert_to(&target),
+        letterbox.canvas,
+        canvas_row_len as i32,
+        target_size,
+        alloc,
+    )?;
+    Ok(input)
+}
+
+/// The classes TOML, e.g. `models/classes.toml`
+#[derive(Deserialize, Debug)]
+pub struct Classes {
+    pub classes: Vec<String>,
+    /// score thresholds by class name, overriding the global one
+    #[serde(default)]
+    pub thresholds: HashMap<String, f32>,
+    /// input size, blob names and normalization of the network
+    #[serde(default)]
+    pub model: Option<ModelSection>,
+}
+
+impl Classes {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read classes file `{}`", path))?;
+        let classes = toml::from_str::<Classes>(&text)
+            .with_context(|| format!("malformed classes file `{}`", path))?;
+        Ok(classes)
+    }
+
+    /// `architecture` is used when the `[model]` table doesn't declare one
+    pub fn model_config(&self, architecture: Architecture) -> Result<ModelConfig> {
+        match self.model {
+            Some(ref model) => model.resolve(architecture),
+            None => Ok(ModelConfig::default_for(architecture)),
+        }
+    }
+
+    pub fn class_thresholds(&self) -> Result<ClassThresholds> {
+        ClassThresholds::from_names(&self.thresholds, &self.classes)
+    }
+}
+
+/// Per class score thresholds, indexed by `TargetBox::class`
+#[derive(Debug, Clone, Default)]
+pub struct ClassThresholds(Vec<Option<f32>>);
+
+impl ClassThresholds {
+    pub fn from_names(thresholds: &HashMap<String, f32>, classes: &[String]) -> Result<Self> {
+        let mut resolved = vec![None; classes.len()];
+        for (name, threshold) in thresholds.iter() {
+            match classes.iter().position(|c| c == name) {
+                Some(idx) => resolved[idx] = Some(*threshold),
+                None => anyhow::bail!("unknown class `{}` in thresholds", name),
+            }
+        }
+        Ok(ClassThresholds(resolved))
+    }
+
+    pub fn get(&self, class: i32, default: f32) -> f32 {
+        match self.0.get(class as usize) {
+            Some(Some(threshold)) => *threshold,
+            _ => default,
+        }
+    }
+
+    /// the threshold to run `ImageModel::detect` with, so that no class is cut too early
+    pub fn min(&self, default: f32) -> f32 {
+        self.0.iter().flatten().fold(default, |a, b| a.min(*b))
+    }
+
+    /// drop the targets under the threshold of their class
+    pub fn retain(&self, targets: &mut Vec<TargetBox>, default: f32) {
+        targets.retain(|t| t.score > self.get(t.class, default));
+    }
+}
+
+/// The classes to report, from lists of class names or indices
+///
+/// Kept by label rather than by index, so it still means the same classes when
+/// the model is reloaded with another class list.
+#[derive(Debug, Clone, Default)]
+pub struct ClassFilter {
+    /// every class when empty
+    include: HashSet<String>,
+    exclude: HashSet<String>,
+}
+
+impl ClassFilter {
+    pub fn new(include: &[String], exclude: &[String], classes: &[String]) -> Result<Self> {
+        let resolve = |names: &[String]| -> Result<HashSet<String>> {
+            names
+                .iter()
+                .map(|name| resolve_class(name, classes))
+                .collect()
+        };
+        Ok(ClassFilter {
+            include: resolve(include)?,
+            exclude: resolve(exclude)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// drop the targets of the classes which are not included or are excluded
+    pub fn retain(&self, targets: &mut Vec<TargetBox>, classes: &[String]) {
+        if self.is_empty() {
+            return;
+        }
+        targets.retain(|t| match classes.get(t.class as usize) {
+            Some(label) => {
+                (self.include.is_empty() || self.include.contains(label))
+                    && !self.exclude.contains(label)
+            }
+            None => self.include.is_empty(),
+        });
+    }
+}
+
+/// The label of `name`, which is either a label or an index into `classes`
+fn resolve_class(name: &str, classes: &[String]) -> Result<String> {
+    if classes.iter().any(|c| c == name) {
+        return Ok(name.to_string());
+    }
+    match name.parse::<usize>() {
+        Ok(idx) => match classes.get(idx) {
+            Some(label) => Ok(label.clone()),
+            None => anyhow::bail!("class index {} is out of the {} classes", idx, classes.len()),
+        },
+        Err(_) => anyhow::bail!("unknown class `{}`", name),
+    }
+}
+
+/// Split a comma separated list of classes, e.g. `person, car,2`
+pub fn parse_class_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TargetBox {
     pub x1: i32,
@@ -23,6 +335,9 @@ pub struct TargetBox {
     pub y2: i32,
     pub score: f32,
     pub class: i32,
+    /// set by the tracker, see `track::Tracker`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_id: Option<u64>,
 }
 
 impl TargetBox {
@@ -38,35 +353,259 @@ impl TargetBox {
     pub fn intersection_area(&self, other: &TargetBox) -> i32 {
         intersection_area(self, other)
     }
+    /// Intersection over Union, 0 for empty boxes
+    pub fn iou(&self, other: &TargetBox) -> f32 {
+        let intersection = self.intersection_area(other);
+        let union = self.area() + other.area() - intersection;
+        // avoid divide by zero (in real world, this should not happen)
+        if union != 0 {
+            intersection as f32 / union as f32
+        } else {
+            0.0
+        }
+    }
 }
 
 pub fn paint_targets<T: Deref<Target = [u8]> + DerefMut<Target = [u8]>>(
     paint_img: &mut RgbBuffer<T>,
     targets: &Vec<TargetBox>,
     classes: &Vec<String>,
+    style: &PaintStyle,
+    mode: PaintMode,
 ) -> Result<(), anyhow::Error> {
+    draw_targets(paint_img, targets, classes, style, mode, Rgb)
+}
+
+/// Paint on samples of any `PixelFormat`
+///
+/// Gray samples are painted with the luma of the colors of `style`. For
+/// NV12/I420 frames this is the luma plane, `redact_chroma` should follow on
+/// the chroma planes.
+pub fn paint_samples(
+    img: &mut FlatSamples<&mut [u8]>,
+    format: PixelFormat,
+    targets: &Vec<TargetBox>,
+    classes: &Vec<String>,
+    style: &PaintStyle,
+    mode: PaintMode,
+) -> Result<(), anyhow::Error> {
+    let bgr = |[r, g, b]: [u8; 3]| [b, g, r];
+    let luma = |[r, g, b]: [u8; 3]| {
+        (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32).round() as u8
+    };
+    match format {
+        PixelFormat::Rgb => {
+            let mut view = img.as_view_mut::<Rgb<u8>>()?;
+            draw_targets(&mut view, targets, classes, style, mode, Rgb)
+        }
+        PixelFormat::Bgr => {
+            let mut view = img.as_view_mut::<Rgb<u8>>()?;
+            draw_targets(&mut view, targets, classes, style, mode, |c| Rgb(bgr(c)))
+        }
+        PixelFormat::Rgba => {
+            let mut view = img.as_view_mut::<Rgba<u8>>()?;
+            draw_targets(&mut view, targets, classes, style, mode, |c| Rgb(c).to_rgba())
+        }
+        PixelFormat::Bgra => {
+            let mut view = img.as_view_mut::<Rgba<u8>>()?;
+            draw_targets(&mut view, targets, classes, style, mode, |c| Rgb(bgr(c)).to_rgba())
+        }
+        PixelFormat::Gray => {
+            let mut view = img.as_view_mut::<Luma<u8>>()?;
+            draw_targets(&mut view, targets, classes, style, mode, |c| Luma([luma(c)]))
+        }
+    }
+}
+
+/// Which samples a chroma plane of a 4:2:0 frame holds, see `redact_chroma`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaPlane {
+    /// NV12, U and V interleaved
+    Uv,
+    /// I420
+    U,
+    V,
+}
+
+/// BT.601 limited range U and V of a RGB color, as `utils::yuv420_to_rgb` reads them
+fn chroma([r, g, b]: [u8; 3]) -> [u8; 2] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    [u.clamp(0, 255) as u8, v.clamp(0, 255) as u8]
+}
+
+/// Redact the targets on a chroma plane of a NV12/I420 frame, half the width
+/// and height of the frame, like `paint_samples` did on its luma plane
+///
+/// Otherwise the colors of a blurred or filled target would still show its
+/// outline. Boxes and labels are only painted on the luma plane.
+pub fn redact_chroma(
+    img: &mut FlatSamples<&mut [u8]>,
+    plane: ChromaPlane,
+    targets: &[TargetBox],
+    classes: &[String],
+    style: &PaintStyle,
+    mode: PaintMode,
+) -> Result<(), anyhow::Error> {
+    match plane {
+        ChromaPlane::Uv => {
+            let mut view = img.as_view_mut::<LumaA<u8>>()?;
+            let pixel = |c| LumaA(chroma(c));
+            redact_targets(&mut view, targets, classes, style, mode, &pixel, 2);
+        }
+        ChromaPlane::U => {
+            let mut view = img.as_view_mut::<Luma<u8>>()?;
+            let pixel = |c| Luma([chroma(c)[0]]);
+            redact_targets(&mut view, targets, classes, style, mode, &pixel, 2);
+        }
+        ChromaPlane::V => {
+            let mut view = img.as_view_mut::<Luma<u8>>()?;
+            let pixel = |c| Luma([chroma(c)[1]]);
+            redact_targets(&mut view, targets, classes, style, mode, &pixel, 2);
+        }
+    }
+    Ok(())
+}
+
+fn class_name<'a>(classes: &'a [String], target: &TargetBox) -> &'a str {
+    classes
+        .get(target.class as usize)
+        .map(|c| c.as_str())
+        .unwrap_or("unknown")
+}
+
+/// Blur, pixelate or fill the targets whose mode isn't `Boxes`, on a canvas
+/// `subsampling` times smaller than the frame of the boxes
+fn redact_targets<C, F>(
+    canvas: &mut C,
+    targets: &[TargetBox],
+    classes: &[String],
+    style: &PaintStyle,
+    mode: PaintMode,
+    pixel: &F,
+    subsampling: i32,
+) where
+    C: Canvas,
+    C::Pixel: Pixel<Subpixel = u8>,
+    F: Fn([u8; 3]) -> C::Pixel,
+{
     for target in targets.iter() {
-        let (x1, y1, x2, y2) = (target.x1, target.y1, target.x2, target.y2);
-        let rect = imageproc::rect::Rect::at(x1 as i32, y1 as i32)
-            .of_size((x2 - x1).try_into().unwrap(), (y2 - y1).try_into().unwrap())
-            .try_into()?;
-        let color_text = image::Rgb([242, 255, 128]);
-        let color = image::Rgb([0, 255, 2]);
-        imageproc::drawing::draw_hollow_rect_mut(paint_img, rect, color);
-        let class_name = classes.index(target.class as usize);
-        let font = Font::try_from_bytes(&FONT).ok_or(anyhow::anyhow!("font error"))?;
-        let height = 24.8;
-        let scale = Scale {
-            x: height,
-            y: height,
-        };
-        imageproc::drawing::draw_text_mut(
-            paint_img, color_text, x1 as i32, y1 as i32, scale, &font, class_name,
+        let class_name = class_name(classes, target);
+        let mode = style.mode(class_name, mode);
+        if mode == PaintMode::Boxes {
+            continue;
+        }
+        // rounded outwards, a chroma sample covering any of the box is redacted
+        let s = subsampling;
+        let corners = (
+            target.x1.div_euclid(s),
+            target.y1.div_euclid(s),
+            (target.x2 + s - 1).div_euclid(s),
+            (target.y2 + s - 1).div_euclid(s),
         );
+        let region = match redact::clip(canvas, corners) {
+            Some(region) => region,
+            None => continue,
+        };
+        let (x, y, w, h) = region;
+        // the same strength as on the full resolution plane
+        let size = |w: u32, h: u32| {
+            let s = s as u32;
+            (style.redact_size(w * s, h * s) / s).max(1)
+        };
+        match mode {
+            PaintMode::Blur => redact::blur(canvas, region, size(w, h)),
+            PaintMode::Pixelate => redact::pixelate(canvas, region, size(w, h)),
+            _ => {
+                let color = pixel(style.color(target.class, class_name));
+                let rect = Rect::at(x as i32, y as i32).of_size(w, h);
+                imageproc::drawing::draw_filled_rect_mut(canvas, rect, color);
+            }
+        }
+    }
+}
+
+/// `pixel` converts the RGB colors of `style` to the pixel type of `canvas`
+///
+/// `mode` is for the classes without one in the style. The targets to redact
+/// are done first, so that they don't cover the boxes of the others, and
+/// they get no box nor label.
+pub fn draw_targets<C, F>(
+    canvas: &mut C,
+    targets: &Vec<TargetBox>,
+    classes: &Vec<String>,
+    style: &PaintStyle,
+    mode: PaintMode,
+    pixel: F,
+) -> Result<(), anyhow::Error>
+where
+    C: Canvas,
+    C::Pixel: Pixel<Subpixel = u8>,
+    F: Fn([u8; 3]) -> C::Pixel,
+{
+    redact_targets(canvas, targets, classes, style, mode, &pixel, 1);
+    let font = style.font();
+    let scale = Scale::uniform(style.font_height(canvas.height()));
+    let boxes = targets
+        .iter()
+        .filter(|target| style.mode(class_name(classes, target), mode) == PaintMode::Boxes);
+    for target in boxes {
+        let class_name = class_name(classes, target);
+        let color = style.color(target.class, class_name);
+        draw_box(canvas, target, style, pixel(color));
+        let label = style.label(target, class_name);
+        if label.is_empty() {
+            continue;
+        }
+        let font = font.ok_or(anyhow::anyhow!("font error"))?;
+        let color_text = pixel(style.text_color(color));
+        let (x, mut y) = (target.x1, target.y1);
+        if style.config.label_background {
+            let (w, h) = imageproc::drawing::text_size(scale, font, &label);
+            // above the box if it fits in the frame, inside otherwise
+            if y >= h {
+                y -= h;
+            }
+            let background = Rect::at(x, y).of_size(w.max(1) as u32, h.max(1) as u32);
+            imageproc::drawing::draw_filled_rect_mut(canvas, background, pixel(color));
+        }
+        imageproc::drawing::draw_text_mut(canvas, color_text, x, y, scale, font, &label);
     }
     Ok(())
 }
 
+/// The outline of `target`, `thickness` wide inside the box
+fn draw_box<C: Canvas>(canvas: &mut C, target: &TargetBox, style: &PaintStyle, color: C::Pixel) {
+    let (x1, y1, x2, y2) = (target.x1, target.y1, target.x2, target.y2);
+    let (w, h) = (target.width(), target.height());
+    if w <= 0 || h <= 0 {
+        return;
+    }
+    let t = (style.config.thickness as i32).min(w.min(h) / 2).max(1);
+    let mut fill = |x: i32, y: i32, w: i32, h: i32| {
+        let rect = Rect::at(x, y).of_size(w as u32, h as u32);
+        imageproc::drawing::draw_filled_rect_mut(canvas, rect, color);
+    };
+    match style.config.corners {
+        Corners::Square => {
+            fill(x1, y1, w, t);
+            fill(x1, y2 - t, w, t);
+            fill(x1, y1, t, h);
+            fill(x2 - t, y1, t, h);
+        }
+        Corners::Brackets => {
+            let l = (w.min(h) / 4).max(t);
+            // -1 on the right and the bottom, where the brackets grow back
+            let corners = [(x1, y1, 0, 0), (x2, y1, -1, 0), (x1, y2, 0, -1), (x2, y2, -1, -1)];
+            for (x, y, dx, dy) in corners {
+                fill(x + dx * l, y + dy * t, l, t);
+                fill(x + dx * t, y + dy * l, t, l);
+            }
+        }
+    }
+}
+
 pub fn intersection_area(a: &TargetBox, b: &TargetBox) -> i32 {
     if a.x1 > b.x2 || a.x2 < b.x1 || a.y1 > b.y2 || a.y2 < b.y1 {
         // no intersection
@@ -86,15 +625,7 @@ pub fn nms_handle(boxes: &[TargetBox], nms_threshold: f32) -> Vec<TargetBox> {
     for target_box in sorted_boxes.iter() {
         let mut keep = true;
         for picked_box in picked.iter() {
-            let intersection = intersection_area(target_box, picked_box);
-            let union = target_box.area() + picked_box.area() - intersection;
-            // Intersection over Union
-            // avoid divide by zero (in real world, this should not happen)
-            let iou = if union != 0 {
-                intersection as f32 / union as f32
-            } else {
-                0.0
-            };
+            let iou = target_box.iou(picked_box);
             if iou > nms_threshold && target_box.class == picked_box.class {
                 keep = false;
                 break;
@@ -107,12 +638,29 @@ pub fn nms_handle(boxes: &[TargetBox], nms_threshold: f32) -> Vec<TargetBox> {
     picked
 }
 
-pub trait ImageModel {
-    fn preprocess<T: Deref<Target = [u8]> + AsRef<[u8]>>(
-        &self,
-        img: &RgbBuffer<T>,
-    ) -> Result<Mat>;
+/// `Send` is required since the element keeps a `Box<dyn ImageModel>` behind a `Mutex`
+pub trait ImageModel: Send {
+    /// `img` is usually obtained by `RgbBuffer::as_flat_samples`, and `format`
+    /// describes how its samples are laid out
+    fn preprocess(&self, img: &FlatSamples<&[u8]>, format: PixelFormat) -> Result<Mat>;
 
     fn detect(&mut self, input: &Mat, img_size: (i32, i32), thresh: f32) -> Result<Vec<TargetBox>>;
     fn labels(&self) -> &Vec<String>;
 }
+
+/// The best class of an `ImageClassifier`, indexed into its `labels`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Classification {
+    pub class: i32,
+    pub score: f32,
+}
+
+/// Like `ImageModel` but for a second stage network, which tells one class for
+/// the whole (usually cropped) image instead of boxes
+pub trait ImageClassifier: Send {
+    fn preprocess(&self, img: &FlatSamples<&[u8]>, format: PixelFormat) -> Result<Mat>;
+
+    /// The score of every class of `labels`
+    fn classify(&mut self, input: &Mat) -> Result<Vec<f32>>;
+    fn labels(&self) -> &Vec<String>;
+}
@@ -0,0 +1,363 @@
+// The `[model]` table of the classes TOML, describing how to feed and read the network
+use super::common::{letterbox_pixels, resize_pixels, ImageModel, Letterbox, PixelFormat};
+use super::fastest_det::FastestDet;
+use super::int8::ParamFile;
+use super::nanodet::NanoDet;
+use super::runtime::RuntimeOptions;
+use super::yolo_fastest::YoloFastest;
+use super::yolov5::Yolov5;
+use super::yolov8::Yolov8;
+use anyhow::{bail, Context, Result};
+use image::flat::FlatSamples;
+use ncnn_rs::{Allocator as NcnnAllocator, Mat, MatPixelType};
+use serde_derive::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Architecture {
+    FastestDet,
+    YoloFastest,
+    Yolov5,
+    Yolov8,
+    NanodetPlus,
+}
+
+/// Channel order the network was trained with
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelOrder {
+    Rgb,
+    Bgr,
+}
+
+impl ChannelOrder {
+    pub fn to_ncnn(self) -> MatPixelType {
+        match self {
+            ChannelOrder::Rgb => MatPixelType::RGB,
+            ChannelOrder::Bgr => MatPixelType::BGR,
+        }
+    }
+}
+
+/// Everything the models need besides the weights and the class list
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub architecture: Architecture,
+    /// (width, height)
+    pub input_size: (i32, i32),
+    pub input_blob: String,
+    pub output_blobs: Vec<String>,
+    pub mean_vals: Vec<f32>,
+    pub norm_vals: Vec<f32>,
+    pub channel_order: ChannelOrder,
+    /// keep the aspect ratio of the frame, see `Letterbox`
+    pub letterbox: bool,
+    /// RGB color of the letterbox padding
+    pub pad_color: [u8; 3],
+    /// strides of the heads, smallest first, for YOLOv5/YOLOv8/NanoDet
+    pub strides: Vec<i32>,
+    /// (width, height) pairs in input pixels, head by head, for YOLOv5/Yolo-FastestV2
+    pub anchors: Vec<f32>,
+    pub anchors_per_head: usize,
+    /// size of the box distributions, for YOLOv8/NanoDet, which don't count
+    /// the same: YOLOv8 has `reg_max` bins per side (16), NanoDet-Plus counts
+    /// from 0 to `reg_max`, so `reg_max + 1` bins (7, so 8 bins)
+    pub reg_max: i32,
+}
+
+impl ModelConfig {
+    /// The pretrained models of upstream
+    pub fn default_for(architecture: Architecture) -> Self {
+        let config = ModelConfig {
+            architecture,
+            input_size: (352, 352),
+            input_blob: "input.1".to_string(),
+            output_blobs: vec![],
+            mean_vals: vec![0.0, 0.0, 0.0],
+            norm_vals: vec![1.0 / 255.0, 1.0 / 255.0, 1.0 / 255.0],
+            channel_order: ChannelOrder::Rgb,
+            letterbox: false,
+            pad_color: [114, 114, 114],
+            strides: vec![],
+            anchors: vec![],
+            anchors_per_head: 3,
+            reg_max: 0,
+        };
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+        match architecture {
+            // https://github.com/dog-qiuqiu/FastestDet/blob/50473cd155cb088aa4a99e64ff6a4b3c24fa07e1/example/ncnn/FastestDet.cpp#L142
+            Architecture::FastestDet => ModelConfig {
+                output_blobs: names(&["758"]),
+                ..config
+            },
+            // https://github.com/dog-qiuqiu/Yolo-FastestV2/blob/main/sample/ncnn/src/yolo-fastestv2.cpp
+            Architecture::YoloFastest => ModelConfig {
+                output_blobs: names(&["794", "796"]),
+                channel_order: ChannelOrder::Bgr,
+                anchors: vec![
+                    12.64, 19.39, 37.88, 51.48, 55.71, 138.31, 126.91, 78.23, 131.57, 214.55,
+                    279.92, 258.87,
+                ],
+                ..config
+            },
+            // https://github.com/Tencent/ncnn/blob/master/examples/yolov5.cpp
+            Architecture::Yolov5 => ModelConfig {
+                input_size: (640, 640),
+                input_blob: "images".to_string(),
+                output_blobs: names(&["output", "781", "801"]),
+                letterbox: true,
+                strides: vec![8, 16, 32],
+                anchors: vec![
+                    10.0, 13.0, 16.0, 30.0, 33.0, 23.0, 30.0, 61.0, 62.0, 45.0, 59.0, 119.0, 116.0,
+                    90.0, 156.0, 198.0, 373.0, 326.0,
+                ],
+                ..config
+            },
+            // https://github.com/FeiGeChuanShu/ncnn-android-yolov8
+            Architecture::Yolov8 => ModelConfig {
+                input_size: (640, 640),
+                input_blob: "images".to_string(),
+                output_blobs: names(&["output"]),
+                letterbox: true,
+                strides: vec![8, 16, 32],
+                reg_max: 16,
+                ..config
+            },
+            // https://github.com/RangiLyu/nanodet/blob/main/demo_ncnn/nanodet.cpp
+            Architecture::NanodetPlus => ModelConfig {
+                input_size: (416, 416),
+                input_blob: "data".to_string(),
+                output_blobs: names(&["output"]),
+                mean_vals: vec![103.53, 116.28, 123.675],
+                norm_vals: vec![0.017429, 0.017507, 0.017125],
+                channel_order: ChannelOrder::Bgr,
+                strides: vec![8, 16, 32, 64],
+                reg_max: 7,
+                ..config
+            },
+        }
+    }
+
+    /// `resize_pixels` or `letterbox_pixels`, then normalize
+    pub fn preprocess(
+        &self,
+        img: &FlatSamples<&[u8]>,
+        format: PixelFormat,
+        alloc: Option<&NcnnAllocator>,
+    ) -> Result<Mat> {
+        let target = self.channel_order.to_ncnn();
+        let mut input = if self.letterbox {
+            letterbox_pixels(img, format, target, self.input_size, self.pad_color, alloc)?
+        } else {
+            resize_pixels(img, format, target, self.input_size, alloc)?
+        };
+        input.substract_mean_normalize(&self.mean_vals, &self.norm_vals);
+        Ok(input)
+    }
+
+    /// The size the boxes should be decoded to, and the letterbox to undo
+    /// afterwards if any
+    pub fn decode_size(&self, img_size: (i32, i32)) -> ((i32, i32), Option<Letterbox>) {
+        if self.letterbox {
+            let letterbox = Letterbox::new(img_size, self.input_size);
+            (letterbox.canvas, Some(letterbox))
+        } else {
+            (img_size, None)
+        }
+    }
+}
+
+/// The `[model]` table as written, e.g.
+///
+/// ```toml
+/// [model]
+/// architecture = "fastest-det"
+/// width = 512
+/// height = 512
+/// input = "images"
+/// outputs = ["output"]
+/// mean = [0.0, 0.0, 0.0]
+/// norm = [0.00392157, 0.00392157, 0.00392157]
+/// channel-order = "rgb"
+/// letterbox = true
+/// pad-color = [114, 114, 114]
+/// # YOLOv5/YOLOv8/NanoDet only
+/// strides = [8, 16, 32]
+/// # YOLOv5/Yolo-FastestV2 only
+/// anchors = [10, 13, 16, 30, 33, 23, 30, 61, 62, 45, 59, 119, 116, 90, 156, 198, 373, 326]
+/// anchors-per-head = 3
+/// # YOLOv8/NanoDet only, the bins per side for YOLOv8 but one less for
+/// # NanoDet, e.g. 7 for its 8 bins, see `ModelConfig::reg_max`
+/// reg-max = 16
+/// ```
+///
+/// Missing keys fall back to `ModelConfig::default_for` the architecture.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ModelSection {
+    pub architecture: Option<Architecture>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub input: Option<String>,
+    pub outputs: Option<Vec<String>>,
+    pub mean: Option<Vec<f32>>,
+    pub norm: Option<Vec<f32>>,
+    pub channel_order: Option<ChannelOrder>,
+    pub letterbox: Option<bool>,
+    pub pad_color: Option<[u8; 3]>,
+    pub strides: Option<Vec<i32>>,
+    pub anchors: Option<Vec<f32>>,
+    pub anchors_per_head: Option<usize>,
+    pub reg_max: Option<i32>,
+}
+
+impl ModelSection {
+    /// `architecture` is used when the table doesn't declare one
+    pub fn resolve(&self, architecture: Architecture) -> Result<ModelConfig> {
+        let architecture = self.architecture.unwrap_or(architecture);
+        let default = ModelConfig::default_for(architecture);
+        let config = ModelConfig {
+            architecture,
+            input_size: (
+                self.width.unwrap_or(default.input_size.0),
+                self.height.unwrap_or(default.input_size.1),
+            ),
+            input_blob: self.input.clone().unwrap_or(default.input_blob),
+            output_blobs: self.outputs.clone().unwrap_or(default.output_blobs),
+            mean_vals: self.mean.clone().unwrap_or(default.mean_vals),
+            norm_vals: self.norm.clone().unwrap_or(default.norm_vals),
+            channel_order: self.channel_order.unwrap_or(default.channel_order),
+            letterbox: self.letterbox.unwrap_or(default.letterbox),
+            pad_color: self.pad_color.unwrap_or(default.pad_color),
+            strides: self.strides.clone().unwrap_or(default.strides),
+            anchors: self.anchors.clone().unwrap_or(default.anchors),
+            anchors_per_head: self.anchors_per_head.unwrap_or(default.anchors_per_head),
+            reg_max: self.reg_max.unwrap_or(default.reg_max),
+        };
+        let (width, height) = config.input_size;
+        if width <= 0 || height <= 0 {
+            bail!("invalid input size {}x{}", width, height);
+        }
+        if config.mean_vals.len() != 3 || config.norm_vals.len() != 3 {
+            bail!("`mean` and `norm` should have 3 values, one per channel");
+        }
+        let strides = config.strides.len();
+        if config.strides.iter().any(|s| *s <= 0) {
+            bail!("invalid strides {:?}", config.strides);
+        }
+        let outputs_ok = match architecture {
+            Architecture::FastestDet => config.output_blobs.len() == 1,
+            Architecture::YoloFastest => !config.output_blobs.is_empty(),
+            Architecture::Yolov5 => strides > 0 && config.output_blobs.len() == strides,
+            // either all the points in one output or one output per stride
+            Architecture::Yolov8 | Architecture::NanodetPlus => {
+                strides > 0
+                    && (config.output_blobs.len() == 1 || config.output_blobs.len() == strides)
+            }
+        };
+        if !outputs_ok {
+            bail!(
+                "output blobs {:?} don't match {:?} with strides {:?}",
+                config.output_blobs,
+                architecture,
+                config.strides
+            );
+        }
+        let heads = match architecture {
+            Architecture::Yolov5 => Some(strides),
+            Architecture::YoloFastest => Some(config.output_blobs.len()),
+            _ => None,
+        };
+        if let Some(heads) = heads {
+            let expected = heads * config.anchors_per_head * 2;
+            if config.anchors_per_head == 0 || config.anchors.len() != expected {
+                bail!(
+                    "{} heads of {} anchors need {} anchor values (width, height pairs), got {}",
+                    heads,
+                    config.anchors_per_head,
+                    expected,
+                    config.anchors.len()
+                );
+            }
+        }
+        if matches!(
+            architecture,
+            Architecture::Yolov8 | Architecture::NanodetPlus
+        ) && config.reg_max <= 0
+        {
+            bail!("`reg-max` should be positive, got {}", config.reg_max);
+        }
+        Ok(config)
+    }
+}
+
+/// Load the `ImageModel` of `config.architecture`, run with `runtime`
+///
+/// Int8 models made by ncnn2int8 load like the fp32 ones, see `int8::calibrate`
+/// for their calibration table.
+pub fn load_model<P: AsRef<str>>(
+    param_path: P,
+    model_path: P,
+    config: &ModelConfig,
+    runtime: &RuntimeOptions,
+    classes: Vec<String>,
+) -> Result<Box<dyn ImageModel>> {
+    let model = load_architecture(&param_path, &model_path, config, runtime, classes);
+    // the int8 layers are only there if ncnn was built with NCNN_INT8
+    let is_quantized = || {
+        ParamFile::from_file(param_path.as_ref())
+            .map(|param| param.is_quantized())
+            .unwrap_or(false)
+    };
+    if model.is_err() && is_quantized() {
+        return model.context("the model is int8 quantized, is ncnn built with NCNN_INT8?");
+    }
+    model
+}
+
+fn load_architecture<P: AsRef<str>>(
+    param_path: P,
+    model_path: P,
+    config: &ModelConfig,
+    runtime: &RuntimeOptions,
+    classes: Vec<String>,
+) -> Result<Box<dyn ImageModel>> {
+    let model: Box<dyn ImageModel> = match config.architecture {
+        Architecture::FastestDet => Box::new(FastestDet::new(
+            param_path,
+            model_path,
+            config.clone(),
+            runtime,
+            classes,
+        )?),
+        Architecture::YoloFastest => Box::new(YoloFastest::new(
+            param_path,
+            model_path,
+            config.clone(),
+            runtime,
+            classes,
+        )?),
+        Architecture::Yolov5 => Box::new(Yolov5::new(
+            param_path,
+            model_path,
+            config.clone(),
+            runtime,
+            classes,
+        )?),
+        Architecture::Yolov8 => Box::new(Yolov8::new(
+            param_path,
+            model_path,
+            config.clone(),
+            runtime,
+            classes,
+        )?),
+        Architecture::NanodetPlus => Box::new(NanoDet::new(
+            param_path,
+            model_path,
+            config.clone(),
+            runtime,
+            classes,
+        )?),
+    };
+    Ok(model)
+}
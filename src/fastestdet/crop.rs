@@ -0,0 +1,52 @@
+// Cut the targets out of the frames for `crop_src`
+use super::common::{PixelFormat, TargetBox};
+use image::flat::FlatSamples;
+use image::imageops::FilterType;
+use image::{Rgb, RgbImage};
+
+/// x, y, width and height in the frame
+pub type Region = (u32, u32, u32, u32);
+
+/// The part of `target` within a `width` x `height` frame, if any
+pub fn crop_region(target: &TargetBox, width: u32, height: u32) -> Option<Region> {
+    let x1 = target.x1.clamp(0, width as i32) as u32;
+    let y1 = target.y1.clamp(0, height as i32) as u32;
+    let x2 = target.x2.clamp(0, width as i32) as u32;
+    let y2 = target.y2.clamp(0, height as i32) as u32;
+    (x2 > x1 && y2 > y1).then(|| (x1, y1, x2 - x1, y2 - y1))
+}
+
+/// Rows of packed RGB are padded to 4 bytes in the default layout of GStreamer
+pub fn rgb_stride(width: u32) -> usize {
+    (width as usize * 3 + 3) & !3
+}
+
+/// `region` of `img` stretched to `width` x `height`, as packed RGB rows of
+/// `rgb_stride` bytes
+pub fn crop_rgb(
+    img: &FlatSamples<&[u8]>,
+    format: PixelFormat,
+    (x0, y0, w, h): Region,
+    (width, height): (u32, u32),
+) -> Vec<u8> {
+    let layout = img.layout;
+    let crop = RgbImage::from_fn(w, h, |x, y| {
+        let at = (y0 + y) as usize * layout.height_stride + (x0 + x) as usize * layout.width_stride;
+        let s = &img.samples[at..at + format.channels() as usize];
+        match format {
+            PixelFormat::Rgb | PixelFormat::Rgba => Rgb([s[0], s[1], s[2]]),
+            PixelFormat::Bgr | PixelFormat::Bgra => Rgb([s[2], s[1], s[0]]),
+            PixelFormat::Gray => Rgb([s[0], s[0], s[0]]),
+        }
+    });
+    let resized = image::imageops::resize(&crop, width, height, FilterType::Triangle);
+    let (row, stride) = (width as usize * 3, rgb_stride(width));
+    let mut data = vec![0; stride * height as usize];
+    for (dst, src) in data
+        .chunks_exact_mut(stride)
+        .zip(resized.as_raw().chunks_exact(row))
+    {
+        dst[..row].copy_from_slice(src);
+    }
+    data
+}
@@ -1,17 +1,20 @@
 // adapted from
 // https://github.com/dog-qiuqiu/FastestDet/blob/main/example/ncnn/FastestDet.cpp
-use super::common::{ImageModel, RgbBuffer, TargetBox};
+use super::common::{ImageModel, PixelFormat, TargetBox};
+use super::config::ModelConfig;
+use super::runtime::RuntimeOptions;
 use super::utils::*;
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use ncnn_rs::{Allocator as NcnnAllocator, Mat, Net};
-use std::ops::{Deref, Index};
+use image::flat::FlatSamples;
+use std::ops::Index;
 
 pub struct FastestDet {
     alloc: NcnnAllocator,
     net: Net,
     classes: Vec<String>,
-    /// 模型输入宽高
-    model_size: (i32, i32),
+    /// input size, blob names and normalization
+    config: ModelConfig,
 }
 
 // Maybe I should use mutex instead
@@ -21,40 +24,22 @@ unsafe impl Sync for FastestDet {}
 impl ImageModel for FastestDet {
     // https://github.com/Tencent/ncnn/blob/bae2ee375fe025776d18a489a92a7f2357af7312/src/c_api.h#L103
     /// I assume you will read it from image crate with
-    fn preprocess<T: Deref<Target = [u8]> + AsRef<[u8]>>(&self, img: &RgbBuffer<T>) -> Result<Mat> {
-        let mean_vals: Vec<f32> = vec![0.0, 0.0, 0.0];
-        let norm_vals: Vec<f32> = vec![1.0 / 255.0, 1.0 / 255.0, 1.0 / 255.0];
-        let img_size = (img.width() as i32, img.height() as i32);
-        let img_data = img.as_flat_samples().samples;
-        let (_, _, height_stride) = img.as_flat_samples().strides_cwh();
-        let stride = height_stride;
-        use ncnn_rs::MatPixelType;
-        let mut input = Mat::from_pixels_resize(
-            img_data,
-            MatPixelType::RGB.to_int(),
-            img_size,
-            stride as i32,
-            self.model_size,
-            Some(&self.alloc),
-        )?;
-        input.substract_mean_normalize(&mean_vals, &norm_vals);
-        Ok(input)
+    fn preprocess(&self, img: &FlatSamples<&[u8]>, format: PixelFormat) -> Result<Mat> {
+        self.config.preprocess(img, format, Some(&self.alloc))
     }
 
     fn detect(&mut self, input: &Mat, img_size: (i32, i32), thresh: f32) -> Result<Vec<TargetBox>> {
         let mut ex = self.net.create_extractor();
-        // magic string
-        // https://github.com/dog-qiuqiu/FastestDet/blob/50473cd155cb088aa4a99e64ff6a4b3c24fa07e1/example/ncnn/FastestDet.cpp#L142
-        if let Err(e) = ex.input("input.1", input) {
+        if let Err(e) = ex.input(&self.config.input_blob, input) {
             bail!("ex.input error: {}", e);
         };
         let mut output = Mat::new();
-        // magic name
-        if let Err(e) = ex.extract("758", &mut output) {
+        if let Err(e) = ex.extract(&self.config.output_blobs[0], &mut output) {
             bail!("ex.extract error: {}", e);
         };
         let mut target_boxes: Vec<TargetBox> = Vec::new();
-        let (img_width, img_height) = img_size;
+        // boxes are decoded on the letterbox canvas, if any, then moved back
+        let ((img_width, img_height), letterbox) = self.config.decode_size(img_size);
         let out_h = output.h();
         let out_w = output.w();
         let class_num = self.classes.len();
@@ -103,11 +88,15 @@ impl ImageModel for FastestDet {
                         y2,
                         score,
                         class: class_index,
+                        track_id: None,
                     };
                     target_boxes.push(target_box);
                 }
             }
         }
+        if let Some(letterbox) = letterbox {
+            letterbox.unmap(&mut target_boxes);
+        }
         Ok(target_boxes)
     }
 
@@ -117,12 +106,12 @@ impl ImageModel for FastestDet {
 }
 
 impl FastestDet {
-    /// model_size: (width, height)
+    /// `config` should have one output blob, see `ModelSection::resolve`
     pub fn new<P>(
         param_path: P,
         model_path: P,
-        // model_size should be 352*352 for pretrained FastestDet
-        model_size: (i32, i32),
+        config: ModelConfig,
+        runtime: &RuntimeOptions,
         classes: Vec<String>,
     ) -> Result<Self>
     where
@@ -130,12 +119,16 @@ impl FastestDet {
     {
         let mut det = FastestDet {
             alloc: unsafe { NcnnAllocator::new() },
-            net: Net::new(),
+            net: runtime.net(),
             classes,
-            model_size,
+            config,
         };
-        det.net.load_param(param_path.as_ref())?;
-        det.net.load_model(model_path.as_ref())?;
+        det.net
+            .load_param(param_path.as_ref())
+            .with_context(|| format!("failed to load param file `{}`", param_path.as_ref()))?;
+        det.net
+            .load_model(model_path.as_ref())
+            .with_context(|| format!("failed to load model file `{}`", model_path.as_ref()))?;
         Ok(det)
     }
 }
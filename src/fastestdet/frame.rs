@@ -0,0 +1,139 @@
+// Helpers to hand the mapped video frames to `ImageModel` and `paint_samples`
+use super::common::{ChromaPlane, PixelFormat};
+use super::utils::yuv420_to_rgb;
+use gst_video::VideoFormat;
+use image::flat::{FlatSamples, SampleLayout};
+use std::borrow::Cow;
+
+/// Raw video formats accepted by the element
+pub const SUPPORTED_FORMATS: [VideoFormat; 9] = [
+    VideoFormat::Rgb,
+    VideoFormat::Bgr,
+    VideoFormat::Rgbx,
+    VideoFormat::Bgrx,
+    VideoFormat::Rgba,
+    VideoFormat::Bgra,
+    VideoFormat::Nv12,
+    VideoFormat::I420,
+    VideoFormat::Gray8,
+];
+
+/// Layout of the first plane, which is the one to be painted
+///
+/// For NV12/I420 it's the luma plane, so the targets are painted in gray.
+pub fn plane_format(format: VideoFormat) -> Option<PixelFormat> {
+    match format {
+        VideoFormat::Rgb => Some(PixelFormat::Rgb),
+        VideoFormat::Bgr => Some(PixelFormat::Bgr),
+        VideoFormat::Rgbx | VideoFormat::Rgba => Some(PixelFormat::Rgba),
+        VideoFormat::Bgrx | VideoFormat::Bgra => Some(PixelFormat::Bgra),
+        VideoFormat::Nv12 | VideoFormat::I420 | VideoFormat::Gray8 => Some(PixelFormat::Gray),
+        _ => None,
+    }
+}
+
+/// The chroma planes of a NV12/I420 frame by index, for `common::redact_chroma`
+pub fn chroma_planes(format: VideoFormat) -> &'static [(u32, ChromaPlane)] {
+    match format {
+        VideoFormat::Nv12 => &[(1, ChromaPlane::Uv)],
+        VideoFormat::I420 => &[(1, ChromaPlane::U), (2, ChromaPlane::V)],
+        _ => &[],
+    }
+}
+
+/// Describe a chroma plane of a `width` x `height` 4:2:0 frame
+pub fn chroma_samples<T>(
+    data: T,
+    plane: ChromaPlane,
+    width: u32,
+    height: u32,
+    stride: usize,
+) -> FlatSamples<T> {
+    let channels = match plane {
+        ChromaPlane::Uv => 2,
+        ChromaPlane::U | ChromaPlane::V => 1,
+    };
+    FlatSamples {
+        samples: data,
+        layout: SampleLayout {
+            channels,
+            channel_stride: 1,
+            width: (width + 1) / 2,
+            width_stride: channels as usize,
+            height: (height + 1) / 2,
+            height_stride: stride,
+        },
+        color_hint: None,
+    }
+}
+
+/// whether the frame needs to be converted by `yuv_frame_to_rgb` before inference
+pub fn is_yuv420(format: VideoFormat) -> bool {
+    matches!(format, VideoFormat::Nv12 | VideoFormat::I420)
+}
+
+/// Describe a plane whose rows are `stride` bytes apart
+///
+/// GStreamer pads the rows to a multiple of 4 bytes, so `stride` may be larger
+/// than `width * channels` for RGB/BGR/GRAY8 of odd width.
+pub fn plane_samples<T>(
+    data: T,
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    stride: usize,
+) -> FlatSamples<T> {
+    let channels = format.channels();
+    FlatSamples {
+        samples: data,
+        layout: SampleLayout {
+            channels,
+            channel_stride: 1,
+            width,
+            width_stride: channels as usize,
+            height,
+            height_stride: stride,
+        },
+        color_hint: None,
+    }
+}
+
+/// Convert a NV12/I420 frame to packed RGB for the models
+pub fn yuv_frame_to_rgb(
+    frame: &gst_video::VideoFrameRef<&mut gst::BufferRef>,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let size = (frame.width() as usize, frame.height() as usize);
+    let stride = |plane: usize| frame.plane_stride()[plane] as usize;
+    let y = (frame.plane_data(0)?, stride(0));
+    let rgb = match frame.format() {
+        VideoFormat::Nv12 => {
+            let uv = frame.plane_data(1)?;
+            yuv420_to_rgb(y, (uv, stride(1)), (&uv[1..], stride(1)), 2, size)
+        }
+        VideoFormat::I420 => {
+            let u = (frame.plane_data(1)?, stride(1));
+            let v = (frame.plane_data(2)?, stride(2));
+            yuv420_to_rgb(y, u, v, 1, size)
+        }
+        format => anyhow::bail!("{:?} is not a 4:2:0 format", format),
+    };
+    Ok(rgb)
+}
+
+/// Samples handed to the model, with their layout and row stride
+///
+/// The first plane is borrowed as is, NV12/I420 are converted to packed RGB.
+pub fn input_samples<'a>(
+    frame: &'a gst_video::VideoFrameRef<&mut gst::BufferRef>,
+) -> Result<(Cow<'a, [u8]>, PixelFormat, usize), anyhow::Error> {
+    let format = frame.format();
+    if is_yuv420(format) {
+        let rgb = yuv_frame_to_rgb(frame)?;
+        Ok((Cow::Owned(rgb), PixelFormat::Rgb, frame.width() as usize * 3))
+    } else {
+        let plane_format =
+            plane_format(format).ok_or(anyhow::anyhow!("unsupported format {:?}", format))?;
+        let stride = frame.plane_stride()[0] as usize;
+        Ok((Cow::Borrowed(frame.plane_data(0)?), plane_format, stride))
+    }
+}
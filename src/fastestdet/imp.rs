@@ -1,21 +1,39 @@
 use gst::glib;
 use rand::rngs::StdRng;
 // use gst::glib::subclass::prelude::*;
-use super::common::{nms_handle, paint_targets, ImageModel, RgbBuffer, TargetBox};
-use super::fastest_det::FastestDet;
+use super::analytics::{Analytics, AnalyticsConfig, AnalyticsEvent, Direction, FrameAnalytics};
+use super::common::{
+    nms_handle, paint_samples, parse_class_list, redact_chroma, ClassFilter, ClassThresholds,
+    Classes, ImageModel, PixelFormat, TargetBox,
+};
+use super::config::{load_model, Architecture};
+use super::crop;
+use super::runtime::RuntimeOptions;
+use super::frame;
+use super::schedule::InferenceSchedule;
+use super::shared::Registry;
+use super::style::PaintStyle;
+use super::tile::{self, TileConfig};
+use super::track::{TrackEvent, TrackEventKind, Tracker, TrackerConfig};
+use super::watch::FileWatcher;
+use super::worker::InferenceWorker;
+use super::{Backend, DropPolicy, ModelStatus, PaintMode, TileMerge};
+use anyhow::Context;
 use gst::prelude::*;
 use gst::subclass::prelude::*;
 use gst::{debug, info, warning};
 use gst_base::subclass::prelude::*;
 use gst_video::subclass::prelude::*;
+use image::flat::FlatSamples;
 use once_cell::sync::Lazy;
 use rand::distributions::{Distribution, Uniform};
 use rand::{Rng, SeedableRng};
-use serde_derive::Deserialize;
+use serde_derive::Serialize;
 use std::i32;
 use std::ops::Not;
-use std::ops::{Deref, DerefMut};
-use std::sync::Mutex;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
 // VideoInfo is a struct that contains various fields like width/height,
 // framerate and the video format and allows to conveniently with the
@@ -34,24 +52,226 @@ static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     )
 });
 
-#[derive(Deserialize, Debug)]
-struct Classes {
-    pub classes: Vec<String>,
-}
-
 const DEFAULT_MODEL_PATH: &'static str = "models.bin";
 const DEFAULT_PARAM_PATH: &'static str = "models.param";
 const DEFAULT_CLASSES_PATH: &'static str = "classes.toml";
+const DEFAULT_BACKEND: Backend = Backend::FastestDet;
+const DEFAULT_SCORE_THRESHOLD: f32 = 0.65;
+const DEFAULT_NMS_THRESHOLD: f32 = 0.45;
+const DEFAULT_QUEUE_DEPTH: u32 = 1;
+const DEFAULT_DROP_POLICY: DropPolicy = DropPolicy::DropOldest;
+const DEFAULT_PAINT_MODE: PaintMode = PaintMode::Boxes;
+const DEFAULT_CROP_SIZE: u32 = 224;
+const DEFAULT_WATCH_INTERVAL: u32 = 1000;
+
+/// The models loaded with a `model-id`, see `SharedModel`
+static SHARED_MODELS: Lazy<Registry<SharedModel>> = Lazy::new(Registry::default);
+
+/// The loaded detector, shared with the async inference worker
+pub struct Detector {
+    model: Mutex<Box<dyn ImageModel>>,
+    /// copied from `ImageModel::labels` so they can be read while the model is busy
+    labels: Vec<String>,
+    /// overrides of `score-threshold` from the classes file
+    class_thresholds: ClassThresholds,
+}
+
+impl Detector {
+    fn new(model: Box<dyn ImageModel>, class_thresholds: ClassThresholds) -> Self {
+        let labels = model.labels().clone();
+        Detector {
+            model: Mutex::new(model),
+            labels,
+            class_thresholds,
+        }
+    }
+}
+
+type Worker = InferenceWorker<InferenceJob, InferenceResult>;
+
+/// The worker running `InferenceJob`s for one element, or for all the elements
+/// of a `SharedModel`
+fn spawn_worker() -> std::io::Result<Worker> {
+    InferenceWorker::spawn("fastestdet-infer", |job: InferenceJob| {
+        let (width, height) = (job.info.width, job.info.height);
+        let start = Instant::now();
+        let targets = {
+            let img = frame::plane_samples(&job.data[..], job.format, width, height, job.stride);
+            let mut model = job.det.model.lock().unwrap();
+            let class_thresholds = &job.det.class_thresholds;
+            GstFastestDet::detect(model.as_mut(), &img, job.format, &job.thresholds, class_thresholds)
+        };
+        let elapsed = start.elapsed();
+        InferenceResult {
+            targets,
+            info: job.info,
+            det: job.det,
+            elapsed,
+            frame: job.keep_frame.then(|| (job.data, job.format, job.stride)),
+        }
+    })
+}
+
+/// A model loaded once for all the elements of the process with the same `model-id`
+///
+/// In async mode their frames go to the same worker, which serves the elements
+/// in turn. In sync mode they take turns on the model lock.
+pub struct SharedModel {
+    /// the files the model was loaded from, by the first element
+    config: LoadConfig,
+    /// replaced when any of the elements reloads
+    det: Mutex<Arc<Detector>>,
+    /// spawned for the first element in async mode
+    worker: Mutex<Option<Arc<Worker>>>,
+}
+
+impl SharedModel {
+    fn detector(&self) -> Arc<Detector> {
+        self.det.lock().unwrap().clone()
+    }
+
+    fn worker(&self) -> std::io::Result<Arc<Worker>> {
+        let mut worker = self.worker.lock().unwrap();
+        match *worker {
+            Some(ref worker) => Ok(worker.clone()),
+            None => {
+                let spawned = Arc::new(spawn_worker()?);
+                *worker = Some(spawned.clone());
+                Ok(spawned)
+            }
+        }
+    }
+}
+
+/// What `try_get_det` reads from `Settings`, copied so the model can be built
+/// without holding the lock
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadConfig {
+    model_path: String,
+    param_path: String,
+    classes_path: String,
+    backend: Backend,
+    runtime: RuntimeOptions,
+}
+
+/// Thresholds and tiling read from `Settings` when the frame is detected
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    score: f32,
+    nms: f32,
+    /// detect on tiles of the frame rather than on the whole of it
+    tiling: Option<TileConfig>,
+}
 
 pub struct Settings {
     model_path: String,
     param_path: String,
     classes_path: String,
+    backend: Backend,
     is_paint: bool,
+    /// for the classes without a mode in `style`
+    paint_mode: PaintMode,
+    /// inline TOML of `paint-style`, used when `style_path` is empty
+    style_config: String,
+    style_path: String,
+    style: PaintStyle,
+    score_threshold: f32,
+    nms_threshold: f32,
+    /// see `tile::detect_tiled`
+    tiling: bool,
+    tile_config: TileConfig,
+    /// class names or indices, every class when empty
+    include_classes: Vec<String>,
+    exclude_classes: Vec<String>,
+    /// the two lists above, checked against the classes of the model
+    class_filter: ClassFilter,
     rng: StdRng,
     last_state: Vec<TargetBox>,
+    /// the frame `last_state` was detected on
+    last_source: Option<FrameInfo>,
+    /// the detector `last_state` was detected by, its classes may differ from
+    /// the current one after a reload
+    last_det: Weak<Detector>,
+    /// legacy random frame skipping, applied on top of `schedule`
     dropout: f32,
-    det: Option<FastestDet>,
+    schedule: InferenceSchedule,
+    is_async: bool,
+    queue_depth: u32,
+    drop_policy: DropPolicy,
+    /// whether the model should be loaded, see `GstFastestDet::load_det`
+    run: bool,
+    status: ModelStatus,
+    last_error: Option<String>,
+    /// bumped by every load/unload so an outdated reload is not swapped in
+    load_generation: u64,
+    watch_files: bool,
+    /// polling interval of `watch_files` in milliseconds
+    watch_interval: u32,
+    /// give the targets a `track_id`, see `Tracker`
+    track: bool,
+    tracker: Tracker,
+    /// inline TOML of `analytics`, used when `analytics_path` is empty
+    analytics_config: String,
+    analytics_path: String,
+    /// zones and lines, if any were configured
+    analytics: Option<Analytics>,
+    /// size of the buffers pushed on `crop_src`
+    crop_width: u32,
+    crop_height: u32,
+    /// ncnn options, applied on the next load
+    runtime: RuntimeOptions,
+    /// empty for a model of its own, see `SharedModel`
+    model_id: String,
+    shared: Option<Arc<SharedModel>>,
+    /// the private model, when `model_id` is empty
+    det: Option<Arc<Detector>>,
+}
+
+impl Settings {
+    fn thresholds(&self) -> Thresholds {
+        Thresholds {
+            score: self.score_threshold,
+            nms: self.nms_threshold,
+            tiling: self.tiling.then_some(self.tile_config),
+        }
+    }
+
+    /// the detector frames should be run on, if loaded
+    fn detector(&self) -> Option<Arc<Detector>> {
+        match self.shared {
+            Some(ref shared) => Some(shared.detector()),
+            None => self.det.clone(),
+        }
+    }
+
+    /// `include_classes` and `exclude_classes` resolved against `labels`
+    fn class_filter(&self, labels: &[String]) -> anyhow::Result<ClassFilter> {
+        ClassFilter::new(&self.include_classes, &self.exclude_classes, labels)
+            .context("invalid include-classes or exclude-classes")
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.shared.is_some() || self.det.is_some()
+    }
+
+    fn load_config(&self) -> LoadConfig {
+        LoadConfig {
+            model_path: self.model_path.clone(),
+            param_path: self.param_path.clone(),
+            classes_path: self.classes_path.clone(),
+            backend: self.backend,
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    /// files checked by `watch-files`
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        vec![
+            PathBuf::from(&self.param_path),
+            PathBuf::from(&self.model_path),
+            PathBuf::from(&self.classes_path),
+        ]
+    }
 }
 
 impl Default for Settings {
@@ -60,124 +280,1149 @@ impl Default for Settings {
             model_path: DEFAULT_MODEL_PATH.to_string(),
             param_path: DEFAULT_PARAM_PATH.to_string(),
             classes_path: DEFAULT_CLASSES_PATH.to_string(),
+            backend: DEFAULT_BACKEND,
             is_paint: false,
+            paint_mode: DEFAULT_PAINT_MODE,
+            style_config: String::new(),
+            style_path: String::new(),
+            style: PaintStyle::default(),
+            score_threshold: DEFAULT_SCORE_THRESHOLD,
+            nms_threshold: DEFAULT_NMS_THRESHOLD,
+            tiling: false,
+            tile_config: TileConfig::default(),
+            include_classes: vec![],
+            exclude_classes: vec![],
+            class_filter: ClassFilter::default(),
             rng: StdRng::from_entropy(),
             last_state: vec![],
+            last_source: None,
+            last_det: Weak::new(),
             dropout: 0.0,
+            schedule: InferenceSchedule::default(),
+            is_async: false,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            drop_policy: DEFAULT_DROP_POLICY,
+            run: false,
+            status: ModelStatus::Unloaded,
+            last_error: None,
+            load_generation: 0,
+            watch_files: false,
+            watch_interval: DEFAULT_WATCH_INTERVAL,
+            track: false,
+            tracker: Tracker::default(),
+            analytics_config: String::new(),
+            analytics_path: String::new(),
+            analytics: None,
+            crop_width: DEFAULT_CROP_SIZE,
+            crop_height: DEFAULT_CROP_SIZE,
+            runtime: RuntimeOptions::default(),
+            model_id: String::new(),
+            shared: None,
             det: None,
         }
     }
 }
 
+/// Streaming state of `text_pad` and `crop_src`, reset when the element stops
+pub struct State {
+    /// number of the video frames seen so far
+    frame_count: u64,
+    stream_started: bool,
+    /// the last segment received on the sink pad, forwarded to `text_pad`
+    /// before the next json buffer
+    segment: Option<gst::Segment>,
+    segment_pending: bool,
+    /// the same for `crop_src`
+    crop_stream_started: bool,
+    crop_segment_pending: bool,
+    /// the size of the last caps sent on `crop_src`
+    crop_caps: Option<(u32, u32)>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            frame_count: 0,
+            stream_started: false,
+            segment: None,
+            segment_pending: true,
+            crop_stream_started: false,
+            crop_segment_pending: true,
+            crop_caps: None,
+        }
+    }
+}
+
+/// Information of the source video frame which is sent along with the targets
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameInfo {
+    pub number: u64,
+    pub pts: Option<gst::ClockTime>,
+    pub dts: Option<gst::ClockTime>,
+    pub duration: Option<gst::ClockTime>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How far a result lags behind the frame it is painted on or reported with
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Staleness {
+    /// frames between the source frame and the current one
+    frames: u64,
+    /// PTS difference in nanoseconds
+    time: Option<u64>,
+}
+
+impl Staleness {
+    fn between(source: &FrameInfo, current: &FrameInfo) -> Self {
+        Staleness {
+            frames: current.number.saturating_sub(source.number),
+            time: match (source.pts, current.pts) {
+                (Some(source), Some(current)) => {
+                    Some(current.nseconds().saturating_sub(source.nseconds()))
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+/// A copy of the frame waiting for the async worker
+struct InferenceJob {
+    data: Vec<u8>,
+    format: PixelFormat,
+    stride: usize,
+    info: FrameInfo,
+    thresholds: Thresholds,
+    det: Arc<Detector>,
+    /// hand `data` back with the result, for `crop_src`
+    keep_frame: bool,
+}
+
+struct InferenceResult {
+    targets: Result<Vec<TargetBox>, anyhow::Error>,
+    info: FrameInfo,
+    det: Arc<Detector>,
+    elapsed: Duration,
+    /// the samples, format and stride of the job if it kept them
+    frame: Option<(Vec<u8>, PixelFormat, usize)>,
+}
+
+#[derive(Serialize, Debug)]
+struct LabeledTarget<'a> {
+    #[serde(flatten)]
+    target: &'a TargetBox,
+    label: &'a str,
+}
+
+/// The json object pushed on `text_pad` for each detected frame
+#[derive(Serialize, Debug)]
+struct FrameResult<'a> {
+    frame: u64,
+    /// PTS of the source frame in nanoseconds
+    timestamp: Option<u64>,
+    /// duration of the source frame in nanoseconds
+    duration: Option<u64>,
+    width: u32,
+    height: u32,
+    /// only reported in async mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    staleness: Option<Staleness>,
+    targets: Vec<LabeledTarget<'a>>,
+    /// only reported with `analytics`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    analytics: Option<&'a FrameAnalytics>,
+}
+
+/// Element messages, json and crops collected while `settings` is locked,
+/// posted and pushed once it isn't
+#[derive(Default)]
+struct PendingMessages {
+    track_events: Vec<TrackEvent>,
+    /// for `text_pad`
+    text: Vec<gst::Buffer>,
+    /// with the frame they were computed on
+    analytics: Vec<(FrameAnalytics, FrameInfo)>,
+    /// for `crop_src`, with their size
+    crops: Vec<(gst::Buffer, (u32, u32))>,
+}
+
 #[derive(Default)]
 pub struct GstFastestDet {
     settings: Mutex<Settings>,
+    state: Mutex<State>,
+    /// the worker of the async mode and the id of our stream on it, the worker
+    /// is shared with other elements when the model is
+    worker: Mutex<Option<(Arc<Worker>, u64)>>,
+    /// polls the model files when `watch-files` is set
+    watcher: Mutex<Option<FileWatcher>>,
     /// `text_pad` here should be an output, which outputs the json of the detected objects.
     /// `src` is the output port of the bin.
     /// `sink` is the input port of the bin.
     /// See also `TargetBox` in `fastest_det.rs`.
     /// See also [why call the output port of a element to "src pad" in gstreamer?](https://superuser.com/questions/1400417/why-call-the-output-port-of-a-element-to-src-pad-in-gstreamer)
     text_pad: Option<gst::Pad>,
+    /// `crop_src`, once requested, gets a RGB buffer per target, see `crop_targets`
+    crop_pad: Mutex<Option<gst::Pad>>,
 }
 
 impl GstFastestDet {
-    pub fn try_get_det(settings: &Settings) -> Result<FastestDet, anyhow::Error> {
-        let classes_text = std::fs::read_to_string(settings.classes_path.clone())?;
-        let classes = toml::from_str::<Classes>(&classes_text)?;
-        let c = classes.classes;
-        // TODO: using config
-        let model_size = (352, 352);
-        let det = FastestDet::new(&settings.param_path, &settings.model_path, model_size, c)?;
-        Ok(det)
-    }
-
-    pub fn send_to_text_pad(&self, targets: &Vec<TargetBox>) -> Result<(), anyhow::Error> {
-        let text_src = self.text_pad.as_ref();
-        if let Some(pad) = text_src {
-            let serialized = serde_json::to_string(targets)?;
-            let buffer = gst::Buffer::from_mut_slice(serialized.into_bytes());
+    pub fn try_get_det(settings: &LoadConfig) -> Result<Detector, anyhow::Error> {
+        let path = &settings.classes_path;
+        let classes = Classes::from_file(path)?;
+        let class_thresholds = classes
+            .class_thresholds()
+            .with_context(|| format!("invalid thresholds in classes file `{}`", path))?;
+        // `backend` is only a fallback, the `[model]` table knows better
+        let architecture = match settings.backend {
+            Backend::FastestDet => Architecture::FastestDet,
+            Backend::YoloFastest => Architecture::YoloFastest,
+            Backend::Yolov5 => Architecture::Yolov5,
+            Backend::Yolov8 => Architecture::Yolov8,
+            Backend::NanodetPlus => Architecture::NanodetPlus,
+        };
+        let config = classes
+            .model_config(architecture)
+            .with_context(|| format!("invalid model table in classes file `{}`", path))?;
+        if config.architecture != architecture {
+            info!(CAT, "using {:?} declared by `{}`", config.architecture, path);
+        }
+        let det = load_model(
+            &settings.param_path,
+            &settings.model_path,
+            &config,
+            &settings.runtime,
+            classes.classes,
+        )?;
+        Ok(Detector::new(det, class_thresholds))
+    }
+
+    /// The model of `config`, or the shared one of `model_id` if not empty
+    fn try_load(
+        config: &LoadConfig,
+        model_id: &str,
+    ) -> Result<(Option<Arc<Detector>>, Option<Arc<SharedModel>>), anyhow::Error> {
+        if model_id.is_empty() {
+            Self::try_get_det(config).map(|det| (Some(Arc::new(det)), None))
+        } else {
+            SHARED_MODELS
+                .get_or_try_insert_with(model_id, || {
+                    Self::try_get_det(config).map(|det| SharedModel {
+                        config: config.clone(),
+                        det: Mutex::new(Arc::new(det)),
+                        worker: Mutex::new(None),
+                    })
+                })
+                .map(|shared| {
+                    if shared.config != *config {
+                        warning!(
+                            CAT,
+                            "model `{}` is already loaded from {:?}, ignoring the paths of this element",
+                            model_id,
+                            shared.config
+                        );
+                    }
+                    (None, Some(shared))
+                })
+        }
+    }
+
+    /// Load the model described by the settings and update its status
+    ///
+    /// Like `reload`, `settings` is only locked to read the paths and to swap
+    /// the model in, so the frames keep flowing meanwhile, and the result is
+    /// dropped if another load or `run=false` came in between. The returned
+    /// error should be posted by the caller, since bus handlers may read the
+    /// properties.
+    fn load_det(&self) -> Result<(), gst::ErrorMessage> {
+        let (config, model_id, generation) = {
+            let mut settings = self.settings.lock().unwrap();
+            settings.load_generation += 1;
+            let model_id = settings.model_id.clone();
+            (settings.load_config(), model_id, settings.load_generation)
+        };
+        let loaded = Self::try_load(&config, &model_id);
+        let mut settings = self.settings.lock().unwrap();
+        if settings.load_generation != generation {
+            debug!(CAT, "model was loaded or unloaded meanwhile, discard load");
+            return Ok(());
+        }
+        // the worker may belong to the previous shared model
+        self.release_worker();
+        // unknown classes fail the load like a bad classes file would
+        let loaded = loaded.and_then(|(det, shared)| {
+            let labels = match (&det, &shared) {
+                (Some(det), _) => det.labels.clone(),
+                (None, Some(shared)) => shared.detector().labels.clone(),
+                (None, None) => vec![],
+            };
+            let class_filter = settings.class_filter(&labels)?;
+            Ok((det, shared, class_filter))
+        });
+        match loaded {
+            Ok((det, shared, class_filter)) => {
+                settings.det = det;
+                settings.shared = shared;
+                settings.class_filter = class_filter;
+                settings.last_state = vec![];
+                settings.last_source = None;
+                settings.status = ModelStatus::Loaded;
+                settings.last_error = None;
+                info!(CAT, "model loaded");
+                Ok(())
+            }
+            Err(e) => {
+                // `{:#}` prints the whole chain, e.g. the path and the ncnn error
+                let message = format!("{:#}", e);
+                gst::error!(CAT, "Failed to create det: {}", message);
+                settings.det = None;
+                settings.shared = None;
+                settings.status = ModelStatus::Error;
+                settings.last_error = Some(message.clone());
+                let is_io = e.root_cause().downcast_ref::<std::io::Error>().is_some();
+                if is_io {
+                    Err(gst::error_msg!(gst::ResourceError::OpenRead, ["{}", message]))
+                } else {
+                    Err(gst::error_msg!(gst::LibraryError::Init, ["{}", message]))
+                }
+            }
+        }
+    }
+
+    /// Build the model again from the current settings and swap it in
+    ///
+    /// Blocks until the model is loaded, the old one keeps serving the frames
+    /// meanwhile. A failure is posted as a warning and the old model is kept.
+    fn reload(&self) {
+        let (config, generation) = {
+            let mut settings = self.settings.lock().unwrap();
+            // nothing to reload, `run` or the state change loads the model
+            if !settings.run || self.obj().current_state() < gst::State::Ready {
+                debug!(CAT, "model is not running, ignore reload");
+                return;
+            }
+            settings.load_generation += 1;
+            // a shared model is reloaded from the files it was loaded from
+            let config = match settings.shared {
+                Some(ref shared) => shared.config.clone(),
+                None => settings.load_config(),
+            };
+            (config, settings.load_generation)
+        };
+        info!(CAT, "reloading model");
+        let result = Self::try_get_det(&config);
+        let mut settings = self.settings.lock().unwrap();
+        if settings.load_generation != generation {
+            debug!(CAT, "model was loaded or unloaded meanwhile, discard reload");
+            return;
+        }
+        let result = result.and_then(|det| {
+            let class_filter = settings.class_filter(&det.labels)?;
+            Ok((det, class_filter))
+        });
+        match result {
+            Ok((det, class_filter)) => {
+                settings.class_filter = class_filter;
+                match settings.shared {
+                    // the other elements pick it up on their next frame
+                    Some(ref shared) => *shared.det.lock().unwrap() = Arc::new(det),
+                    None => settings.det = Some(Arc::new(det)),
+                }
+                // the class list may have changed
+                settings.last_state = vec![];
+                settings.last_source = None;
+                settings.status = ModelStatus::Loaded;
+                settings.last_error = None;
+                info!(CAT, "model reloaded");
+            }
+            Err(e) => {
+                let message = format!("{:#}", e);
+                warning!(CAT, "Failed to reload det: {}", message);
+                settings.last_error = Some(message.clone());
+                drop(settings);
+                gst::element_imp_warning!(
+                    self,
+                    gst::LibraryError::Init,
+                    ["Failed to reload model, keeping the old one: {}", message]
+                );
+            }
+        }
+    }
+
+    /// run `load_det` on its own thread so the caller is not blocked, the
+    /// failure is posted from there
+    fn spawn_load(&self) {
+        let element = self.obj().clone();
+        let spawned = std::thread::Builder::new()
+            .name("fastestdet-load".to_string())
+            .spawn(move || {
+                if let Err(err) = element.imp().load_det() {
+                    element.imp().post_error_message(err);
+                }
+            });
+        if let Err(e) = spawned {
+            gst::error!(CAT, "failed to spawn load thread:{}", e);
+        }
+    }
+
+    /// run `reload` on its own thread so the caller is not blocked
+    fn spawn_reload(&self) {
+        let element = self.obj().clone();
+        let spawned = std::thread::Builder::new()
+            .name("fastestdet-reload".to_string())
+            .spawn(move || element.imp().reload());
+        if let Err(e) = spawned {
+            gst::error!(CAT, "failed to spawn reload thread:{}", e);
+        }
+    }
+
+    /// leave the async worker, which is joined if no other element uses it
+    fn release_worker(&self) {
+        if let Some((worker, stream)) = self.worker.lock().unwrap().take() {
+            worker.unregister(stream);
+        }
+    }
+
+    /// start or stop polling the model files according to `watch-files`
+    fn update_watcher(&self) {
+        let (watch_files, interval) = {
+            let settings = self.settings.lock().unwrap();
+            (settings.watch_files, settings.watch_interval)
+        };
+        let mut watcher = self.watcher.lock().unwrap();
+        // dropping joins the thread, which may be waiting for `settings`
+        *watcher = None;
+        if !watch_files {
+            return;
+        }
+        // weak references, the element owns the watcher
+        let paths_ref = self.obj().downgrade();
+        let reload_ref = paths_ref.clone();
+        let spawned = FileWatcher::spawn(
+            Duration::from_millis(interval.max(1) as u64),
+            move || match paths_ref.upgrade() {
+                Some(element) => element.imp().settings.lock().unwrap().watched_paths(),
+                None => vec![],
+            },
+            move || {
+                if let Some(element) = reload_ref.upgrade() {
+                    info!(CAT, "model files changed");
+                    element.imp().reload();
+                }
+            },
+        );
+        match spawned {
+            Ok(spawned) => *watcher = Some(spawned),
+            Err(e) => gst::error!(CAT, "failed to spawn file watcher:{}", e),
+        }
+    }
+
+    /// Check `include-classes` and `exclude-classes` against the loaded model
+    ///
+    /// `load_det` checks them otherwise. An unknown class is posted as a
+    /// warning and the previous filter is kept, the next load fails on it.
+    fn update_class_filter(&self) {
+        let mut settings = self.settings.lock().unwrap();
+        let det = match settings.detector() {
+            Some(det) => det,
+            None => return,
+        };
+        match settings.class_filter(&det.labels) {
+            Ok(class_filter) => settings.class_filter = class_filter,
+            Err(e) => {
+                let message = format!("{:#}", e);
+                warning!(CAT, "Failed to set class filter: {}", message);
+                drop(settings);
+                gst::element_imp_warning!(
+                    self,
+                    gst::LibraryError::Settings,
+                    ["Keeping the previous class filter: {}", message]
+                );
+            }
+        }
+    }
+
+    /// Load the style of `paint-style-path`, or else of `paint-style`
+    ///
+    /// A malformed style is posted as a warning and the previous one is kept.
+    fn update_paint_style(&self) {
+        let mut settings = self.settings.lock().unwrap();
+        let style = if !settings.style_path.is_empty() {
+            PaintStyle::from_file(&settings.style_path)
+        } else {
+            PaintStyle::parse(&settings.style_config).context("malformed paint-style property")
+        };
+        match style {
+            Ok(style) => settings.style = style,
+            Err(e) => {
+                let message = format!("{:#}", e);
+                warning!(CAT, "Failed to set paint style: {}", message);
+                drop(settings);
+                gst::element_imp_warning!(
+                    self,
+                    gst::LibraryError::Settings,
+                    ["Keeping the previous paint style: {}", message]
+                );
+            }
+        }
+    }
+
+    /// Parse the zones and lines of `analytics-path`, or else of `analytics`
+    ///
+    /// The counts start over. A malformed config is posted as a warning and
+    /// disables the analytics until it is fixed.
+    fn update_analytics(&self) {
+        let mut settings = self.settings.lock().unwrap();
+        let config = if !settings.analytics_path.is_empty() {
+            AnalyticsConfig::from_file(&settings.analytics_path).map(Some)
+        } else if !settings.analytics_config.trim().is_empty() {
+            AnalyticsConfig::parse(&settings.analytics_config)
+                .context("malformed analytics property")
+                .map(Some)
+        } else {
+            Ok(None)
+        };
+        match config {
+            Ok(config) => settings.analytics = config.map(Analytics::new),
+            Err(e) => {
+                let message = format!("{:#}", e);
+                warning!(CAT, "Failed to set analytics: {}", message);
+                settings.analytics = None;
+                drop(settings);
+                gst::element_imp_warning!(
+                    self,
+                    gst::LibraryError::Settings,
+                    ["Analytics disabled: {}", message]
+                );
+            }
+        }
+    }
+
+    /// stream-start, caps and the pending segment if they haven't been sent
+    /// yet, to push once `state` is unlocked
+    fn text_pad_events(&self, pad: &gst::Pad, state: &mut State) -> Vec<gst::Event> {
+        let mut events = vec![];
+        if !state.stream_started {
+            let stream_id = pad.create_stream_id(&*self.obj(), Some("text"));
+            events.push(gst::event::StreamStart::new(&stream_id));
+            let caps = gst::Caps::builder("application/x-json").build();
+            events.push(gst::event::Caps::new(&caps));
+            state.stream_started = true;
+        }
+        if state.segment_pending {
+            let segment = match state.segment {
+                Some(ref segment) => segment.clone(),
+                None => gst::FormattedSegment::<gst::ClockTime>::new().upcast(),
+            };
+            events.push(gst::event::Segment::new(&segment));
+            state.segment_pending = false;
+        }
+        events
+    }
+
+    /// The json of the targets of a frame for `text_pad`, if there is one
+    fn text_pad_buffer(
+        &self,
+        targets: &[TargetBox],
+        labels: &[String],
+        info: &FrameInfo,
+        staleness: Option<Staleness>,
+        analytics: Option<&FrameAnalytics>,
+    ) -> Result<Option<gst::Buffer>, anyhow::Error> {
+        if self.text_pad.is_none() {
+            return Ok(None);
+        }
+        let result = FrameResult {
+            frame: info.number,
+            timestamp: info.pts.map(|t| t.nseconds()),
+            duration: info.duration.map(|t| t.nseconds()),
+            width: info.width,
+            height: info.height,
+            staleness,
+            targets: targets
+                .iter()
+                .map(|target| LabeledTarget {
+                    target,
+                    label: labels
+                        .get(target.class as usize)
+                        .map(|l| l.as_str())
+                        .unwrap_or("unknown"),
+                })
+                .collect(),
+            analytics,
+        };
+        let serialized = serde_json::to_string(&result)?;
+        let mut buffer = gst::Buffer::from_mut_slice(serialized.into_bytes());
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(info.pts);
+            buffer.set_dts(info.dts);
+            buffer.set_duration(info.duration);
+        }
+        Ok(Some(buffer))
+    }
+
+    /// Push the json of `text_pad_buffer`, `settings` and `state` shouldn't be
+    /// locked since downstream may call back into the element
+    fn push_text(&self, buffers: Vec<gst::Buffer>) {
+        let pad = match self.text_pad.as_ref() {
+            Some(pad) if !buffers.is_empty() => pad,
+            _ => return,
+        };
+        for buffer in buffers {
+            let events = self.text_pad_events(pad, &mut self.state.lock().unwrap());
+            for event in events {
+                let _ = pad.push_event(event);
+            }
             // ignore the error
             // if there is no downstream element, the error will be FlowError
             // But we use probe to get the buffer, so no downstream element is ok.
             // No error should be raised.
             let _ = pad.push(buffer);
         }
-        Ok(())
     }
 
     /// kinda pure
     ///
-    /// would return targets filtered by nms
-    pub fn detect<
-        T: Deref<Target = [u8]> + DerefMut<Target = [u8]> + AsRef<[u8]>,
-        M: ImageModel,
-    >(
-        &self,
-        det: &mut M,
-        mat: &mut RgbBuffer<T>,
+    /// would return targets filtered by the (per class) score threshold and nms
+    pub fn detect(
+        det: &mut dyn ImageModel,
+        img: &FlatSamples<&[u8]>,
+        format: PixelFormat,
+        thresholds: &Thresholds,
+        class_thresholds: &ClassThresholds,
     ) -> Result<Vec<TargetBox>, anyhow::Error> {
-        let input = det.preprocess(&mat)?;
-        let (w, h) = (mat.width() as i32, mat.height() as i32);
-        let targets = det.detect(&input, (w, h), 0.65)?;
-        let nms_targets = nms_handle(&targets, 0.45);
+        if let Some(ref tiling) = thresholds.tiling {
+            let (score, nms) = (thresholds.score, thresholds.nms);
+            return tile::detect_tiled(det, img, format, tiling, class_thresholds, (score, nms));
+        }
+        let input = det.preprocess(img, format)?;
+        let (_, w, h) = img.bounds();
+        let thresh = class_thresholds.min(thresholds.score);
+        let mut targets = det.detect(&input, (w as i32, h as i32), thresh)?;
+        class_thresholds.retain(&mut targets, thresholds.score);
+        let nms_targets = nms_handle(&targets, thresholds.nms);
         Ok(nms_targets)
     }
 
+    /// Attach every target to `buffer` as a `GstVideoRegionOfInterestMeta`.
+    ///
+    /// The roi type is the class label and the score/class id are carried by a
+    /// `detection` structure param, along with `age`, the number of frames since
+    /// the targets were detected. Boxes are clamped to the frame since the
+    /// models may predict coordinates out of the image.
+    fn attach_roi_metas(
+        buffer: &mut gst::BufferRef,
+        targets: &[TargetBox],
+        labels: &[String],
+        (cols, rows): (u32, u32),
+        age: u64,
+    ) {
+        for target in targets.iter() {
+            let x1 = target.x1.clamp(0, cols as i32) as u32;
+            let y1 = target.y1.clamp(0, rows as i32) as u32;
+            let x2 = target.x2.clamp(0, cols as i32) as u32;
+            let y2 = target.y2.clamp(0, rows as i32) as u32;
+            if x2 <= x1 || y2 <= y1 {
+                continue;
+            }
+            let label = match labels.get(target.class as usize) {
+                Some(label) => label.as_str(),
+                None => "unknown",
+            };
+            let mut meta = gst_video::VideoRegionOfInterestMeta::add(
+                buffer,
+                label,
+                (x1, y1, x2 - x1, y2 - y1),
+            );
+            for param in Self::roi_params(target, age) {
+                meta.add_param(param);
+            }
+        }
+    }
+
+    /// The `detection` param of the roi meta of `target`, and its `track` if any
+    fn roi_params(target: &TargetBox, age: u64) -> Vec<gst::Structure> {
+        let mut params = vec![gst::Structure::builder("detection")
+            .field("score", target.score as f64)
+            .field("class-id", target.class)
+            .field("age", age)
+            .build()];
+        if let Some(id) = target.track_id {
+            params.push(gst::Structure::builder("track").field("id", id).build());
+        }
+        params
+    }
+
+    /// Post a `fastestdet-track` element message per event, with the `event`
+    /// (`start` or `end`), the `track-id`, the class and the box
+    fn post_track_events(&self, events: &[TrackEvent], labels: &[String], info: &FrameInfo) {
+        for event in events.iter() {
+            let target = &event.target;
+            let kind = match event.kind {
+                TrackEventKind::Start => "start",
+                TrackEventKind::End => "end",
+            };
+            let label = labels
+                .get(target.class as usize)
+                .map(|l| l.as_str())
+                .unwrap_or("unknown");
+            debug!(CAT, "track {:?} {}: {:?}", target.track_id, kind, target);
+            let structure = gst::Structure::builder("fastestdet-track")
+                .field("event", kind)
+                .field("track-id", target.track_id.unwrap_or_default())
+                .field("class-id", target.class)
+                .field("label", label)
+                .field("score", target.score as f64)
+                .field("x", target.x1)
+                .field("y", target.y1)
+                .field("width", target.width())
+                .field("height", target.height());
+            self.post_element_message(structure, info);
+        }
+    }
+
+    /// Post the zones whose occupancy changed and the zone and line events of
+    /// `analytics`, see the `analytics` property
+    ///
+    /// `fastestdet-zone` has the `zone`, the `total` inside and the `counts`
+    /// by `labels`. `fastestdet-zone-event` is an `enter` or `exit` of a
+    /// track, also sent for a track first seen inside or ending inside the
+    /// zone, `fastestdet-line-crossing` a track crossing a line.
+    fn post_analytics(&self, analytics: &FrameAnalytics, info: &FrameInfo) {
+        for zone in analytics.zones.iter().filter(|zone| zone.changed) {
+            let structure = gst::Structure::builder("fastestdet-zone")
+                .field("zone", zone.zone.as_str())
+                .field("total", zone.counts.values().sum::<u32>())
+                .field("labels", gst::Array::new(zone.counts.keys().map(|l| l.as_str())))
+                .field("counts", gst::Array::new(zone.counts.values().copied()));
+            self.post_element_message(structure, info);
+        }
+        let zone_event = |zone: &str, kind: &str, track_id: u64, label: &str| {
+            gst::Structure::builder("fastestdet-zone-event")
+                .field("zone", zone)
+                .field("event", kind)
+                .field("track-id", track_id)
+                .field("label", label)
+        };
+        for event in analytics.events.iter() {
+            debug!(CAT, "analytics event: {:?}", event);
+            let structure = match event {
+                AnalyticsEvent::Enter { zone, track_id, label } => {
+                    zone_event(zone, "enter", *track_id, label)
+                }
+                AnalyticsEvent::Exit { zone, track_id, label } => {
+                    zone_event(zone, "exit", *track_id, label)
+                }
+                AnalyticsEvent::Cross { line, direction, track_id, label } => {
+                    let direction = match direction {
+                        Direction::LeftToRight => "left-to-right",
+                        Direction::RightToLeft => "right-to-left",
+                    };
+                    gst::Structure::builder("fastestdet-line-crossing")
+                        .field("line", line.as_str())
+                        .field("direction", direction)
+                        .field("track-id", *track_id)
+                        .field("label", label.as_str())
+                }
+            };
+            self.post_element_message(structure, info);
+        }
+    }
+
+    /// Post `structure` with the number and the PTS of the frame it is about
+    fn post_element_message(&self, structure: gst::structure::Builder, info: &FrameInfo) {
+        let mut structure = structure.field("frame", info.number);
+        if let Some(pts) = info.pts {
+            structure = structure.field("timestamp", pts.nseconds());
+        }
+        let message = gst::message::Element::builder(structure.build())
+            .src(&*self.obj())
+            .build();
+        let _ = self.obj().post_message(message);
+    }
+
+    /// Filter, track and count the targets detected on `source`, push them on
+    /// `text_pad` and keep them as `last_state`
+    fn handle_targets(
+        &self,
+        settings: &mut Settings,
+        mut targets: Vec<TargetBox>,
+        det: &Arc<Detector>,
+        source: &FrameInfo,
+        staleness: Option<Staleness>,
+        pending: &mut PendingMessages,
+    ) {
+        settings.class_filter.retain(&mut targets, &det.labels);
+        let mut ended = vec![];
+        if settings.track {
+            let events = settings.tracker.update(&mut targets);
+            ended.extend(
+                events
+                    .iter()
+                    .filter(|event| event.kind == TrackEventKind::End)
+                    .map(|event| event.target.clone()),
+            );
+            pending.track_events.extend(events);
+        }
+        let max_misses = settings.tracker.config.max_misses;
+        let analytics = settings.analytics.as_mut().map(|analytics| {
+            analytics.max_misses = max_misses;
+            analytics.update(&targets, &ended, &det.labels)
+        });
+        let text = self.text_pad_buffer(
+            &targets,
+            &det.labels,
+            source,
+            staleness,
+            analytics.as_ref(),
+        );
+        match text {
+            Ok(text) => pending.text.extend(text),
+            Err(e) => gst::error!(CAT, "send to text pad error:{}", e),
+        }
+        if let Some(analytics) = analytics {
+            pending.analytics.push((analytics, *source));
+        }
+        settings.last_state = targets;
+        settings.last_source = Some(*source);
+        settings.last_det = Arc::downgrade(det);
+    }
+
+    /// Cut `last_state` out of the frame it was detected on, if `crop_src` was
+    /// requested
+    ///
+    /// Each crop is stretched to `crop-width` x `crop-height` and carries a
+    /// `GstVideoRegionOfInterestMeta` over the whole crop, with the `detection`
+    /// and `track` params of `attach_roi_metas` and a `parent` param locating
+    /// the box in the source frame.
+    fn crop_targets(
+        &self,
+        settings: &Settings,
+        labels: &[String],
+        (data, format, stride): (&[u8], PixelFormat, usize),
+        pending: &mut PendingMessages,
+    ) {
+        if self.crop_pad.lock().unwrap().is_none() {
+            return;
+        }
+        let source = match settings.last_source {
+            Some(source) => source,
+            None => return,
+        };
+        let img = frame::plane_samples(data, format, source.width, source.height, stride);
+        let size = (settings.crop_width, settings.crop_height);
+        for target in settings.last_state.iter() {
+            let region = crop::crop_region(target, source.width, source.height);
+            let (x, y, width, height) = match region {
+                Some(region) => region,
+                None => continue,
+            };
+            let data = crop::crop_rgb(&img, format, (x, y, width, height), size);
+            let mut buffer = gst::Buffer::from_mut_slice(data);
+            {
+                let buffer = buffer.get_mut().unwrap();
+                buffer.set_pts(source.pts);
+                buffer.set_dts(source.dts);
+                buffer.set_duration(source.duration);
+                let label = labels
+                    .get(target.class as usize)
+                    .map(|l| l.as_str())
+                    .unwrap_or("unknown");
+                let rect = (0, 0, size.0, size.1);
+                let mut meta = gst_video::VideoRegionOfInterestMeta::add(buffer, label, rect);
+                for param in Self::roi_params(target, 0) {
+                    meta.add_param(param);
+                }
+                let mut parent = gst::Structure::builder("parent")
+                    .field("frame", source.number)
+                    .field("x", x)
+                    .field("y", y)
+                    .field("width", width)
+                    .field("height", height)
+                    .field("frame-width", source.width)
+                    .field("frame-height", source.height);
+                if let Some(pts) = source.pts {
+                    parent = parent.field("pts", pts.nseconds());
+                }
+                meta.add_param(parent.build());
+            }
+            pending.crops.push((buffer, size));
+        }
+    }
+
+    /// stream-start, caps for `size` and the pending segment on `crop_src` if
+    /// they haven't been sent yet, to push once `state` is unlocked
+    fn crop_pad_events(
+        &self,
+        pad: &gst::Pad,
+        state: &mut State,
+        size: (u32, u32),
+    ) -> Vec<gst::Event> {
+        let (width, height) = size;
+        let mut events = vec![];
+        if !state.crop_stream_started {
+            let stream_id = pad.create_stream_id(&*self.obj(), Some("crop"));
+            events.push(gst::event::StreamStart::new(&stream_id));
+            state.crop_stream_started = true;
+        }
+        if state.crop_caps != Some((width, height)) {
+            let caps = gst::Caps::builder("video/x-raw")
+                .field("format", gst_video::VideoFormat::Rgb.to_str())
+                .field("width", width as i32)
+                .field("height", height as i32)
+                .field("framerate", gst::Fraction::new(0, 1))
+                .build();
+            events.push(gst::event::Caps::new(&caps));
+            state.crop_caps = Some((width, height));
+        }
+        if state.crop_segment_pending {
+            let segment = match state.segment {
+                Some(ref segment) => segment.clone(),
+                None => gst::FormattedSegment::<gst::ClockTime>::new().upcast(),
+            };
+            events.push(gst::event::Segment::new(&segment));
+            state.crop_segment_pending = false;
+        }
+        events
+    }
+
+    fn push_crops(&self, crops: Vec<(gst::Buffer, (u32, u32))>) {
+        if crops.is_empty() {
+            return;
+        }
+        let pad = match self.crop_pad.lock().unwrap().clone() {
+            Some(pad) => pad,
+            None => return,
+        };
+        for (buffer, size) in crops {
+            let events = self.crop_pad_events(&pad, &mut self.state.lock().unwrap(), size);
+            for event in events {
+                let _ = pad.push_event(event);
+            }
+            // not being linked is fine, as for `text_pad`
+            let _ = pad.push(buffer);
+        }
+    }
+
+    /// Hand the frame to the inference worker and collect the finished results
+    ///
+    /// Frames always go straight through, the targets painted on them are the
+    /// newest results the worker has finished so far.
+    fn transform_async(
+        &self,
+        settings: &mut Settings,
+        det: &Arc<Detector>,
+        frame: &gst_video::VideoFrameRef<&mut gst::BufferRef>,
+        info: &FrameInfo,
+        is_update: bool,
+        pending: &mut PendingMessages,
+    ) -> Result<(), gst::FlowError> {
+        let mut worker = self.worker.lock().unwrap();
+        if worker.is_none() {
+            let spawned = match settings.shared {
+                Some(ref shared) => shared.worker(),
+                None => spawn_worker().map(Arc::new),
+            };
+            match spawned {
+                Ok(spawned) => {
+                    let stream = spawned.register();
+                    *worker = Some((spawned, stream));
+                }
+                Err(e) => {
+                    gst::error!(CAT, "failed to spawn inference worker:{}", e);
+                    return Err(gst::FlowError::Error);
+                }
+            }
+        }
+        let (worker, stream) = worker.as_ref().unwrap();
+        let stream = *stream;
+        if is_update {
+            let (data, format, stride) =
+                frame::input_samples(frame).map_err(|_| gst::FlowError::Error)?;
+            let job = InferenceJob {
+                data: data.into_owned(),
+                format,
+                stride,
+                info: *info,
+                thresholds: settings.thresholds(),
+                det: det.clone(),
+                keep_frame: self.crop_pad.lock().unwrap().is_some(),
+            };
+            let (depth, policy) = (settings.queue_depth as usize, settings.drop_policy);
+            let dropped = worker.submit(stream, job, depth, policy);
+            if dropped > 0 {
+                debug!(CAT, "inference queue is full, {} frame(s) dropped", dropped);
+            }
+        }
+        for result in worker.take_results(stream) {
+            settings.schedule.record_inference(result.elapsed);
+            match result.targets {
+                Ok(targets) => {
+                    let staleness = Staleness::between(&result.info, info);
+                    debug!(
+                        CAT,
+                        "result of frame {} is {} frame(s) stale",
+                        result.info.number,
+                        staleness.frames
+                    );
+                    let (det, source) = (&result.det, &result.info);
+                    self.handle_targets(settings, targets, det, source, Some(staleness), pending);
+                    if let Some((ref data, format, stride)) = result.frame {
+                        self.crop_targets(settings, &det.labels, (data, format, stride), pending);
+                    }
+                }
+                Err(e) => {
+                    gst::error!(CAT, "async detect error:{}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn transform_impl(
         &self,
-        cols: u32,
-        rows: u32,
-        data: &mut [u8],
+        frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>,
     ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let cols = frame.width();
+        let rows = frame.height();
+        let format = frame.format();
+        let plane_format = frame::plane_format(format).ok_or(gst::FlowError::NotNegotiated)?;
+        // rows of the first plane may be padded, see `frame::plane_samples`
+        let stride = frame.plane_stride()[0] as usize;
+        let info = {
+            let mut state = self.state.lock().unwrap();
+            let number = state.frame_count;
+            state.frame_count += 1;
+            let buffer = frame.buffer();
+            FrameInfo {
+                number,
+                pts: buffer.pts(),
+                dts: buffer.dts(),
+                duration: buffer.duration(),
+                width: cols,
+                height: rows,
+            }
+        };
+        // prefer the buffer duration, the framerate could be 0/1 (variable)
+        let frame_duration = info.duration.map(|d| d.nseconds()).or_else(|| {
+            let fps = frame.info().fps();
+            (fps.numer() > 0).then(|| 1_000_000_000 * fps.denom() as u64 / fps.numer() as u64)
+        });
+        let pts = info.pts.map(|t| t.nseconds());
         let mut settings = self.settings.lock().unwrap();
         let is_paint = settings.is_paint;
-        let distribution = Uniform::from(0..100);
-        assert!(settings.dropout >= 0.0 && settings.dropout < 1.0);
-        let p = distribution.sample(&mut settings.rng) as f32 / 100.0;
-        let is_update = if p <= settings.dropout { false } else { true };
-        let last_state = settings.last_state.clone();
-
-        let det = settings.det.as_mut();
-        match det {
-            Some(det) => {
+        let is_due = settings.schedule.is_due(pts, frame_duration);
+        let is_update = is_due && {
+            let distribution = Uniform::from(0..100);
+            let p = distribution.sample(&mut settings.rng) as f32 / 100.0;
+            if p <= settings.dropout { false } else { true }
+        };
+
+        let det = match settings.detector() {
+            Some(det) => det,
+            None => return Ok(gst::FlowSuccess::Ok),
+        };
+        settings.schedule.mark(is_update, pts);
+        // posted once `settings` is unlocked
+        let mut pending = PendingMessages::default();
+        if settings.track {
+            settings.tracker.predict();
+        }
+        if settings.is_async {
+            self.transform_async(&mut settings, &det, frame, &info, is_update, &mut pending)?;
+        } else if is_update {
+            let thresholds = settings.thresholds();
+            let start = Instant::now();
+            let (data, format, stride) = frame::input_samples(frame).map_err(|e| {
+                gst::error!(CAT, "detect error:{}", e);
+                gst::FlowError::Error
+            })?;
+            let result = {
                 // Don't use `to_vec` since it will create new buffer by copy
-                let mut out_mat = image::ImageBuffer::from_raw(cols, rows, data);
-                match out_mat {
-                    Some(ref mut out_mat) => {
-                        if is_update {
-                            match self.detect(det, out_mat) {
-                                Ok(targets) => {
-                                    if is_paint {
-                                        if targets.is_empty().not() {
-                                            debug!(CAT, "painting targets:{:?}", targets);
-                                        }
-                                        let _ = paint_targets(out_mat, &targets, &det.labels());
-                                    }
-                                    // send to text pad only the targets are changed
-                                    match self.send_to_text_pad(&targets) {
-                                        Ok(_) => {}
-                                        Err(e) => {
-                                            gst::error!(CAT, "send to text pad error:{}", e);
-                                        }
-                                    };
-                                    settings.last_state = targets;
-                                    return Ok(gst::FlowSuccess::Ok);
-                                }
-                                Err(_) => return Err(gst::FlowError::Error),
-                            };
-                        } else {
-                            if is_paint {
-                                let _ = paint_targets(out_mat, &last_state, &det.labels());
-                            }
-                        }
-                    }
-                    None => {
-                        return Err(gst::FlowError::Error);
-                    }
+                let img = frame::plane_samples(&data[..], format, cols, rows, stride);
+                let mut model = det.model.lock().unwrap();
+                Self::detect(model.as_mut(), &img, format, &thresholds, &det.class_thresholds)
+            };
+            settings.schedule.record_inference(start.elapsed());
+            match result {
+                Ok(targets) => {
+                    self.handle_targets(&mut settings, targets, &det, &info, None, &mut pending);
+                    let pixels = (&data[..], format, stride);
+                    self.crop_targets(&settings, &det.labels, pixels, &mut pending);
+                }
+                Err(e) => {
+                    gst::error!(CAT, "detect error:{}", e);
+                    return Err(gst::FlowError::Error);
+                }
+            }
+        }
+
+        // another element sharing the model may have reloaded it since
+        if settings.last_det.as_ptr() != Arc::as_ptr(&det) {
+            settings.last_state = vec![];
+            settings.last_source = None;
+            pending.track_events.extend(settings.tracker.clear());
+        }
+        let tracked;
+        let targets = if settings.track {
+            tracked = settings.tracker.targets();
+            &tracked
+        } else {
+            &settings.last_state
+        };
+        if is_paint {
+            let (style, mode) = (&settings.style, settings.paint_mode);
+            // the tracks are only reported once confirmed, but a target to
+            // redact can't wait for `track-min-hits`, so the raw detections
+            // of the redacted classes are painted too
+            let redacted;
+            let targets = if settings.track {
+                let is_redacted = |target: &&TargetBox| {
+                    let label = det.labels.get(target.class as usize);
+                    let label = label.map(|l| l.as_str()).unwrap_or("unknown");
+                    style.mode(label, mode) != PaintMode::Boxes
                 };
+                redacted = settings
+                    .last_state
+                    .iter()
+                    .filter(is_redacted)
+                    .chain(targets.iter())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                &redacted
+            } else {
+                targets
+            };
+            if targets.is_empty().not() {
+                debug!(CAT, "painting targets:{:?}", targets);
+            }
+            // modify the buffer in place
+            let data = frame.plane_data_mut(0).unwrap();
+            let mut img = frame::plane_samples(data, plane_format, cols, rows, stride);
+            let _ = paint_samples(&mut img, plane_format, targets, &det.labels, style, mode);
+            // or the redacted targets would keep their colors
+            for &(index, plane) in frame::chroma_planes(format) {
+                let stride = frame.plane_stride()[index as usize] as usize;
+                let data = frame.plane_data_mut(index).unwrap();
+                let mut img = frame::chroma_samples(data, plane, cols, rows, stride);
+                let _ = redact_chroma(&mut img, plane, targets, &det.labels, style, mode);
             }
-            None => {}
         }
+        // the metas are attached to every outgoing buffer, including the
+        // ones reusing `last_state`, so downstream always sees the current targets
+        let age = match settings.last_source {
+            Some(ref source) => Staleness::between(source, &info).frames,
+            None => 0,
+        };
+        Self::attach_roi_metas(frame.buffer_mut(), targets, &det.labels, (cols, rows), age);
+        drop(settings);
+        self.post_track_events(&pending.track_events, &det.labels, &info);
+        for (analytics, source) in pending.analytics.iter() {
+            self.post_analytics(analytics, source);
+        }
+        self.push_text(pending.text);
+        self.push_crops(pending.crops);
         Ok(gst::FlowSuccess::Ok)
     }
 }
@@ -197,7 +1442,11 @@ impl ObjectSubclass for GstFastestDet {
         let text_pad = gst::Pad::from_template(&templ, Some("text_pad"));
         Self {
             settings: Mutex::new(Settings::default()),
+            state: Mutex::new(State::default()),
+            worker: Mutex::new(None),
+            watcher: Mutex::new(None),
             text_pad: Some(text_pad),
+            crop_pad: Mutex::new(None),
         }
     }
 }
@@ -229,7 +1478,7 @@ impl ObjectImpl for GstFastestDet {
                     .build(),
                 glib::ParamSpecString::builder("config-path")
                     .nick("Config")
-                    .blurb("Config path which should be ended with `.toml`")
+                    .blurb("Config path which should be ended with `.toml`, the class list and optionally a `[model]` table describing the network")
                     .default_value(Some(DEFAULT_CLASSES_PATH))
                     .flags(glib::ParamFlags::READWRITE)
                     .build(),
@@ -239,28 +1488,270 @@ impl ObjectImpl for GstFastestDet {
                     .default_value(Some(DEFAULT_CLASSES_PATH))
                     .flags(glib::ParamFlags::READWRITE)
                     .build(),
+                glib::ParamSpecEnum::builder_with_default("backend", DEFAULT_BACKEND)
+                    .nick("Backend")
+                    .blurb("Which detector to load, takes effect when the model is (re)loaded by `run`. Overridden by `architecture` in the `[model]` table of the config")
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("include-classes")
+                    .nick("Include classes")
+                    .blurb("Comma separated class names or indices to report, every class when empty. Unknown classes fail the model load")
+                    .default_value(Some(""))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("exclude-classes")
+                    .nick("Exclude classes")
+                    .blurb("Comma separated class names or indices not to report, applied after `include-classes`. Unknown classes fail the model load")
+                    .default_value(Some(""))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
                 glib::ParamSpecBoolean::builder("is-paint")
                     .nick("Is paint")
                     .blurb("If true, the recognition result will be painted on the image")
                     .default_value(true)
                     .flags(glib::ParamFlags::READWRITE)
                     .build(),
+                glib::ParamSpecEnum::builder_with_default("paint-mode", DEFAULT_PAINT_MODE)
+                    .nick("Paint mode")
+                    .blurb("How `is-paint` shows the targets, boxes or redacted by blur, pixelate or solid-fill. The `modes` table of the paint style overrides it per class")
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("paint-style")
+                    .nick("Paint style")
+                    .blurb("TOML of the colors, thickness, corners, label template and font of `is-paint`, see `StyleConfig`")
+                    .default_value(Some(""))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("paint-style-path")
+                    .nick("Paint style path")
+                    .blurb("TOML file read instead of `paint-style` when not empty")
+                    .default_value(Some(""))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecFloat::builder("score-threshold")
+                    .nick("Score threshold")
+                    .blurb("Targets with a lower score are dropped, can be overridden per class by `thresholds` in the classes file")
+                    .minimum(0.0)
+                    .maximum(1.0)
+                    .default_value(DEFAULT_SCORE_THRESHOLD)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecFloat::builder("nms-threshold")
+                    .nick("NMS threshold")
+                    .blurb("IoU above which the target with the lower score of the same class is suppressed")
+                    .minimum(0.0)
+                    .maximum(1.0)
+                    .default_value(DEFAULT_NMS_THRESHOLD)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecBoolean::builder("tiling")
+                    .nick("Tiling")
+                    .blurb("Detect on overlapping tiles of the frame, for the small objects of frames much larger than the model input")
+                    .default_value(false)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("tile-width")
+                    .nick("Tile width")
+                    .blurb("Width of the tiles in pixels of the frame")
+                    .minimum(1)
+                    .default_value(TileConfig::default().size.0)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("tile-height")
+                    .nick("Tile height")
+                    .blurb("Height of the tiles in pixels of the frame")
+                    .minimum(1)
+                    .default_value(TileConfig::default().size.1)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecFloat::builder("tile-overlap")
+                    .nick("Tile overlap")
+                    .blurb("Part of a tile shared with the next one")
+                    .minimum(0.0)
+                    .maximum(0.9)
+                    .default_value(TileConfig::default().overlap)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecBoolean::builder("tile-full-frame")
+                    .nick("Tile full frame")
+                    .blurb("Also detect on the whole frame, for the objects larger than a tile")
+                    .default_value(true)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecEnum::builder_with_default("tile-merge", TileMerge::Nms)
+                    .nick("Tile merge")
+                    .blurb("How the boxes of the same object found on several tiles are merged, keeping the best (nms) or enclosing them all (fusion). They match by intersection over the smaller box above `nms-threshold`")
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
                 glib::ParamSpecFloat::builder("dropout")
                     .nick("Dropout rate")
-                    .blurb("Dropout rate. If the value is 0.0, the recognition result will be updated every frame. The higher the value, the lower the update frequency")
+                    .blurb("Legacy, prefer inference-interval. Dropout rate. If the value is 0.0, the recognition result will be updated every frame. The higher the value, the lower the update frequency")
+                    .minimum(0.0)
+                    .maximum(0.99)
+                    .default_value(0.0)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("inference-interval")
+                    .nick("Inference interval")
+                    .blurb("Run inference every N-th frame, the skipped frames reuse the last result")
+                    .minimum(1)
+                    .default_value(1)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt64::builder("inference-period")
+                    .nick("Inference period")
+                    .blurb("Minimum time in nanoseconds between two inferences, based on buffer PTS. 0 to disable")
+                    .default_value(0)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecBoolean::builder("adaptive-interval")
+                    .nick("Adaptive interval")
+                    .blurb("If true, raise the inference interval when the measured inference time is longer than the frame duration")
+                    .default_value(false)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecBoolean::builder("async")
+                    .nick("Async")
+                    .blurb("If true, inference runs on a worker thread and frames are not blocked by the model. The newest results are painted on later frames")
+                    .default_value(false)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("queue-depth")
+                    .nick("Queue depth")
+                    .blurb("Max number of frames waiting for the inference worker in async mode")
+                    .minimum(1)
+                    .maximum(64)
+                    .default_value(DEFAULT_QUEUE_DEPTH)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecEnum::builder_with_default("drop-policy", DEFAULT_DROP_POLICY)
+                    .nick("Drop policy")
+                    .blurb("Which frame to drop when the inference queue is full in async mode")
                     .flags(glib::ParamFlags::READWRITE)
                     .build(),
-                // TODO: use signal to reload model
                 glib::ParamSpecBoolean::builder("run")
                     .nick("Run")
-                    .blurb("if true, try to load and run model")
+                    .blurb("if true, try to load and run model. The model is loaded in the background if the element is at least READY, otherwise when it goes to READY. Failures are posted on the bus")
+                    .default_value(false)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecBoolean::builder("watch-files")
+                    .nick("Watch files")
+                    .blurb("If true, reload the model when the param, model or config file changes. The old model keeps running until the new one is loaded")
+                    .default_value(false)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("watch-interval")
+                    .nick("Watch interval")
+                    .blurb("How often the files are checked by watch-files, in milliseconds")
+                    .minimum(10)
+                    .default_value(DEFAULT_WATCH_INTERVAL)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecBoolean::builder("track")
+                    .nick("Track")
+                    .blurb("Give the targets a track id kept across frames, posting fastestdet-track messages when tracks start and end. The tracked boxes are predicted on the frames without inference")
                     .default_value(false)
                     .flags(glib::ParamFlags::READWRITE)
                     .build(),
+                glib::ParamSpecFloat::builder("track-iou")
+                    .nick("Track IoU")
+                    .blurb("IoU with the predicted box above which a target continues a track")
+                    .minimum(0.0)
+                    .maximum(1.0)
+                    .default_value(TrackerConfig::default().iou_threshold)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("track-min-hits")
+                    .nick("Track min hits")
+                    .blurb("Detections before a track starts")
+                    .minimum(1)
+                    .default_value(TrackerConfig::default().min_hits)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("track-max-misses")
+                    .nick("Track max misses")
+                    .blurb("Inferences in a row a track may miss before it ends, frames without inference don't count")
+                    .default_value(TrackerConfig::default().max_misses)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("analytics")
+                    .nick("Analytics")
+                    .blurb("TOML of the `[[zone]]` polygons and `[[line]]` tripwires to count the targets in and across, see `AnalyticsConfig`. Results are posted as fastestdet-zone, fastestdet-zone-event and fastestdet-line-crossing messages and added to the text output. The events need `track`")
+                    .default_value(Some(""))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("analytics-path")
+                    .nick("Analytics path")
+                    .blurb("TOML file read instead of `analytics` when not empty")
+                    .default_value(Some(""))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("crop-width")
+                    .nick("Crop width")
+                    .blurb("Width the targets pushed on the crop_src request pad are stretched to")
+                    .minimum(1)
+                    .maximum(8192)
+                    .default_value(DEFAULT_CROP_SIZE)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("crop-height")
+                    .nick("Crop height")
+                    .blurb("Height the targets pushed on the crop_src request pad are stretched to")
+                    .minimum(1)
+                    .maximum(8192)
+                    .default_value(DEFAULT_CROP_SIZE)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecUInt::builder("num-threads")
+                    .nick("Threads")
+                    .blurb("Threads used by ncnn, 0 for ncnn's default. Applied when the model is (re)loaded")
+                    .default_value(0)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecBoolean::builder("vulkan")
+                    .nick("Vulkan")
+                    .blurb("Run the model on the GPU with Vulkan, ncnn should be built with it. Applied when the model is (re)loaded")
+                    .default_value(false)
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecString::builder("model-id")
+                    .nick("Model id")
+                    .blurb("If not empty, the elements with the same model id share one model, loaded by the first of them, and in async mode one worker. Empty for a model of its own")
+                    .default_value(Some(""))
+                    .flags(glib::ParamFlags::READWRITE)
+                    .build(),
+                glib::ParamSpecEnum::builder_with_default("status", ModelStatus::Unloaded)
+                    .nick("Status")
+                    .blurb("Whether the model is loaded")
+                    .flags(glib::ParamFlags::READABLE)
+                    .build(),
+                glib::ParamSpecString::builder("last-error")
+                    .nick("Last error")
+                    .blurb("Why the model failed to load, if it did")
+                    .flags(glib::ParamFlags::READABLE)
+                    .build(),
             ]
         });
         PROPERTIES.as_ref()
     }
+
+    fn signals() -> &'static [glib::subclass::Signal] {
+        static SIGNALS: Lazy<Vec<glib::subclass::Signal>> = Lazy::new(|| {
+            // `g_signal_emit_by_name(det, "reload")` reloads the model files
+            // without stopping the pipeline, see `GstFastestDet::reload`
+            vec![glib::subclass::Signal::builder("reload")
+                .action()
+                .class_handler(|_, args| {
+                    let element = args[0].get::<super::GstFastestDet>().unwrap();
+                    element.imp().spawn_reload();
+                    None
+                })
+                .build()]
+        });
+        SIGNALS.as_ref()
+    }
+
     fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
         match pspec.name() {
             "model-path" => {
@@ -285,36 +1776,243 @@ impl ObjectImpl for GstFastestDet {
                 settings.param_path = settings.param_path.trim().to_string();
                 info!(CAT, "Set param path to {}", settings.param_path);
             }
+            "backend" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.backend = value.get::<Backend>().unwrap();
+                info!(CAT, "Set backend to {:?}", settings.backend);
+            }
+            "paint-mode" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.paint_mode = value.get::<PaintMode>().unwrap();
+                info!(CAT, "Set paint mode to {:?}", settings.paint_mode);
+            }
+            "paint-style" => {
+                let mut settings = self.settings.lock().unwrap();
+                let config: Option<String> = value.get().unwrap();
+                settings.style_config = config.unwrap_or_default();
+                info!(CAT, "Set paint style to `{}`", settings.style_config);
+                drop(settings);
+                self.update_paint_style();
+            }
+            "paint-style-path" => {
+                let mut settings = self.settings.lock().unwrap();
+                let path: Option<String> = value.get().unwrap();
+                settings.style_path = path.unwrap_or_default().trim().to_string();
+                info!(CAT, "Set paint style path to `{}`", settings.style_path);
+                drop(settings);
+                self.update_paint_style();
+            }
+            "include-classes" => {
+                let mut settings = self.settings.lock().unwrap();
+                let list: Option<String> = value.get().unwrap();
+                settings.include_classes = parse_class_list(&list.unwrap_or_default());
+                info!(CAT, "Set include classes to {:?}", settings.include_classes);
+                drop(settings);
+                self.update_class_filter();
+            }
+            "exclude-classes" => {
+                let mut settings = self.settings.lock().unwrap();
+                let list: Option<String> = value.get().unwrap();
+                settings.exclude_classes = parse_class_list(&list.unwrap_or_default());
+                info!(CAT, "Set exclude classes to {:?}", settings.exclude_classes);
+                drop(settings);
+                self.update_class_filter();
+            }
             "dropout" => {
                 let mut settings = self.settings.lock().unwrap();
                 settings.dropout = value.get().unwrap();
                 info!(CAT, "Set dropout to {}", settings.dropout);
             }
+            "score-threshold" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.score_threshold = value.get().unwrap();
+                info!(CAT, "Set score threshold to {}", settings.score_threshold);
+            }
+            "nms-threshold" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.nms_threshold = value.get().unwrap();
+                info!(CAT, "Set nms threshold to {}", settings.nms_threshold);
+            }
+            "tiling" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tiling = value.get().unwrap();
+                info!(CAT, "Set tiling to {}", settings.tiling);
+            }
+            "tile-width" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tile_config.size.0 = value.get().unwrap();
+                info!(CAT, "Set tile width to {}", settings.tile_config.size.0);
+            }
+            "tile-height" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tile_config.size.1 = value.get().unwrap();
+                info!(CAT, "Set tile height to {}", settings.tile_config.size.1);
+            }
+            "tile-overlap" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tile_config.overlap = value.get().unwrap();
+                info!(CAT, "Set tile overlap to {}", settings.tile_config.overlap);
+            }
+            "tile-full-frame" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tile_config.full_frame = value.get().unwrap();
+                info!(CAT, "Set tile full frame to {}", settings.tile_config.full_frame);
+            }
+            "tile-merge" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tile_config.merge = value.get().unwrap();
+                info!(CAT, "Set tile merge to {:?}", settings.tile_config.merge);
+            }
+            "inference-interval" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.schedule.interval = value.get().unwrap();
+                info!(CAT, "Set inference interval to {}", settings.schedule.interval);
+            }
+            "inference-period" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.schedule.period = value.get().unwrap();
+                info!(CAT, "Set inference period to {}ns", settings.schedule.period);
+            }
+            "adaptive-interval" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.schedule.adaptive = value.get().unwrap();
+                info!(CAT, "Set adaptive interval to {}", settings.schedule.adaptive);
+            }
             "is-paint" => {
                 let mut settings = self.settings.lock().unwrap();
                 settings.is_paint = value.get().unwrap();
                 info!(CAT, "Set is_paint to {}", settings.is_paint);
             }
+            "async" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.is_async = value.get().unwrap();
+                info!(CAT, "Set async to {}", settings.is_async);
+                let is_async = settings.is_async;
+                drop(settings);
+                if !is_async {
+                    // join the worker, it would be spawned again when needed
+                    self.release_worker();
+                }
+            }
+            "queue-depth" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.queue_depth = value.get().unwrap();
+                info!(CAT, "Set queue depth to {}", settings.queue_depth);
+            }
+            "drop-policy" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.drop_policy = value.get::<DropPolicy>().unwrap();
+                info!(CAT, "Set drop policy to {:?}", settings.drop_policy);
+            }
             "run" => {
                 // https://coaxion.net/blog/2016/09/writing-gstreamer-elements-in-rust-part-2-dont-panic-we-have-better-assertions-now-and-other-updates/
                 let run = value.get().unwrap();
                 let mut settings = self.settings.lock().unwrap();
+                settings.run = run;
                 if run {
-                    let maybe_det = Self::try_get_det(&settings);
-                    match maybe_det {
-                        Ok(det) => {
-                            settings.det = Some(det);
-                            info!(CAT, "model loaded");
-                        }
-                        Err(e) => {
-                            info!(CAT, "Failed to create det: {}", e);
-                            panic!("Failed to create det: {}", e);
-                        }
+                    // there is no bus to report to before READY, see `change_state`
+                    if self.obj().current_state() >= gst::State::Ready {
+                        drop(settings);
+                        self.spawn_load();
                     }
                 } else {
+                    settings.load_generation += 1;
                     settings.det = None;
+                    settings.shared = None;
+                    self.release_worker();
+                    settings.status = ModelStatus::Unloaded;
+                }
+            }
+            "watch-files" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.watch_files = value.get().unwrap();
+                info!(CAT, "Set watch files to {}", settings.watch_files);
+                drop(settings);
+                self.update_watcher();
+            }
+            "watch-interval" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.watch_interval = value.get().unwrap();
+                info!(CAT, "Set watch interval to {}ms", settings.watch_interval);
+                let watch_files = settings.watch_files;
+                drop(settings);
+                if watch_files {
+                    self.update_watcher();
+                }
+            }
+            "track" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.track = value.get().unwrap();
+                info!(CAT, "Set track to {}", settings.track);
+                if !settings.track {
+                    // nothing would end them otherwise
+                    let events = settings.tracker.clear();
+                    let labels = settings.detector().map(|det| det.labels.clone());
+                    drop(settings);
+                    let info = FrameInfo {
+                        number: self.state.lock().unwrap().frame_count,
+                        ..FrameInfo::default()
+                    };
+                    self.post_track_events(&events, &labels.unwrap_or_default(), &info);
                 }
             }
+            "track-iou" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tracker.config.iou_threshold = value.get().unwrap();
+                info!(CAT, "Set track iou to {}", settings.tracker.config.iou_threshold);
+            }
+            "track-min-hits" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tracker.config.min_hits = value.get().unwrap();
+                info!(CAT, "Set track min hits to {}", settings.tracker.config.min_hits);
+            }
+            "track-max-misses" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.tracker.config.max_misses = value.get().unwrap();
+                info!(CAT, "Set track max misses to {}", settings.tracker.config.max_misses);
+            }
+            "analytics" => {
+                let mut settings = self.settings.lock().unwrap();
+                let config: Option<String> = value.get().unwrap();
+                settings.analytics_config = config.unwrap_or_default();
+                info!(CAT, "Set analytics to `{}`", settings.analytics_config);
+                drop(settings);
+                self.update_analytics();
+            }
+            "analytics-path" => {
+                let mut settings = self.settings.lock().unwrap();
+                let path: Option<String> = value.get().unwrap();
+                settings.analytics_path = path.unwrap_or_default().trim().to_string();
+                info!(CAT, "Set analytics path to `{}`", settings.analytics_path);
+                drop(settings);
+                self.update_analytics();
+            }
+            "crop-width" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.crop_width = value.get().unwrap();
+                info!(CAT, "Set crop width to {}", settings.crop_width);
+            }
+            "crop-height" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.crop_height = value.get().unwrap();
+                info!(CAT, "Set crop height to {}", settings.crop_height);
+            }
+            "num-threads" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.runtime.num_threads = value.get().unwrap();
+                info!(CAT, "Set num threads to {}", settings.runtime.num_threads);
+            }
+            "vulkan" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.runtime.vulkan = value.get().unwrap();
+                info!(CAT, "Set vulkan to {}", settings.runtime.vulkan);
+            }
+            "model-id" => {
+                let mut settings = self.settings.lock().unwrap();
+                let model_id: Option<String> = value.get().unwrap();
+                settings.model_id = model_id.unwrap_or_default().trim().to_string();
+                info!(CAT, "Set model id to `{}`", settings.model_id);
+            }
             _ => unimplemented!(),
         }
     }
@@ -333,17 +2031,157 @@ impl ObjectImpl for GstFastestDet {
                 let settings = self.settings.lock().unwrap();
                 settings.param_path.to_value()
             }
+            "backend" => {
+                let settings = self.settings.lock().unwrap();
+                settings.backend.to_value()
+            }
+            "paint-mode" => {
+                let settings = self.settings.lock().unwrap();
+                settings.paint_mode.to_value()
+            }
+            "paint-style" => {
+                let settings = self.settings.lock().unwrap();
+                settings.style_config.to_value()
+            }
+            "paint-style-path" => {
+                let settings = self.settings.lock().unwrap();
+                settings.style_path.to_value()
+            }
+            "include-classes" => {
+                let settings = self.settings.lock().unwrap();
+                settings.include_classes.join(",").to_value()
+            }
+            "exclude-classes" => {
+                let settings = self.settings.lock().unwrap();
+                settings.exclude_classes.join(",").to_value()
+            }
             "dropout" => {
                 let settings = self.settings.lock().unwrap();
                 settings.dropout.to_value()
             }
+            "score-threshold" => {
+                let settings = self.settings.lock().unwrap();
+                settings.score_threshold.to_value()
+            }
+            "nms-threshold" => {
+                let settings = self.settings.lock().unwrap();
+                settings.nms_threshold.to_value()
+            }
+            "tiling" => {
+                let settings = self.settings.lock().unwrap();
+                settings.tiling.to_value()
+            }
+            "tile-width" => {
+                let settings = self.settings.lock().unwrap();
+                settings.tile_config.size.0.to_value()
+            }
+            "tile-height" => {
+                let settings = self.settings.lock().unwrap();
+                settings.tile_config.size.1.to_value()
+            }
+            "tile-overlap" => {
+                let settings = self.settings.lock().unwrap();
+                settings.tile_config.overlap.to_value()
+            }
+            "tile-full-frame" => {
+                let settings = self.settings.lock().unwrap();
+                settings.tile_config.full_frame.to_value()
+            }
+            "tile-merge" => {
+                let settings = self.settings.lock().unwrap();
+                settings.tile_config.merge.to_value()
+            }
+            "inference-interval" => {
+                let settings = self.settings.lock().unwrap();
+                settings.schedule.interval.to_value()
+            }
+            "inference-period" => {
+                let settings = self.settings.lock().unwrap();
+                settings.schedule.period.to_value()
+            }
+            "adaptive-interval" => {
+                let settings = self.settings.lock().unwrap();
+                settings.schedule.adaptive.to_value()
+            }
             "is-paint" => {
                 let settings = self.settings.lock().unwrap();
                 settings.is_paint.to_value()
             }
+            "async" => {
+                let settings = self.settings.lock().unwrap();
+                settings.is_async.to_value()
+            }
+            "queue-depth" => {
+                let settings = self.settings.lock().unwrap();
+                settings.queue_depth.to_value()
+            }
+            "drop-policy" => {
+                let settings = self.settings.lock().unwrap();
+                settings.drop_policy.to_value()
+            }
             "run" => {
                 let settings = self.settings.lock().unwrap();
-                settings.det.is_some().to_value()
+                settings.run.to_value()
+            }
+            "watch-files" => {
+                let settings = self.settings.lock().unwrap();
+                settings.watch_files.to_value()
+            }
+            "watch-interval" => {
+                let settings = self.settings.lock().unwrap();
+                settings.watch_interval.to_value()
+            }
+            "track" => {
+                let settings = self.settings.lock().unwrap();
+                settings.track.to_value()
+            }
+            "track-iou" => {
+                let settings = self.settings.lock().unwrap();
+                settings.tracker.config.iou_threshold.to_value()
+            }
+            "track-min-hits" => {
+                let settings = self.settings.lock().unwrap();
+                settings.tracker.config.min_hits.to_value()
+            }
+            "track-max-misses" => {
+                let settings = self.settings.lock().unwrap();
+                settings.tracker.config.max_misses.to_value()
+            }
+            "analytics" => {
+                let settings = self.settings.lock().unwrap();
+                settings.analytics_config.to_value()
+            }
+            "analytics-path" => {
+                let settings = self.settings.lock().unwrap();
+                settings.analytics_path.to_value()
+            }
+            "crop-width" => {
+                let settings = self.settings.lock().unwrap();
+                settings.crop_width.to_value()
+            }
+            "crop-height" => {
+                let settings = self.settings.lock().unwrap();
+                settings.crop_height.to_value()
+            }
+            "num-threads" => {
+                let settings = self.settings.lock().unwrap();
+                settings.runtime.num_threads.to_value()
+            }
+            "vulkan" => {
+                let settings = self.settings.lock().unwrap();
+                settings.runtime.vulkan.to_value()
+            }
+            "model-id" => {
+                let settings = self.settings.lock().unwrap();
+                settings.model_id.to_value()
+            }
+            "status" => {
+                let settings = self.settings.lock().unwrap();
+                settings.status.to_value()
+            }
+            "last-error" => {
+                let settings = self.settings.lock().unwrap();
+                settings.last_error.to_value()
             }
             _ => unimplemented!(),
         }
@@ -360,6 +2198,23 @@ impl ObjectImpl for GstFastestDet {
 impl GstObjectImpl for GstFastestDet {}
 
 impl ElementImpl for GstFastestDet {
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        if transition == gst::StateChange::NullToReady {
+            let settings = self.settings.lock().unwrap();
+            if settings.run && !settings.is_loaded() {
+                drop(settings);
+                if let Err(err) = self.load_det() {
+                    self.post_error_message(err);
+                    return Err(gst::StateChangeError);
+                }
+            }
+        }
+        self.parent_change_state(transition)
+    }
+
     // there's no set_metadata() in Class now
     // That part is moved to ElementImpl::metadata()
     // The tutorial is outdated
@@ -379,14 +2234,12 @@ impl ElementImpl for GstFastestDet {
     // https://gitlab.freedesktop.org/gstreamer/gstreamer/-/blob/main/subprojects/gst-plugins-bad/gst-libs/gst/opencv/gstopencvutils.cpp#L116
     // https://gstreamer.freedesktop.org/documentation/additional/design/element-transform.html?gi-language=c
     // copy and paste from tutorial
-    // src and sink should only support Rgb (image crate)
+    // src and sink support the packed RGB family, NV12/I420 and GRAY8 (see `frame.rs`)
     fn pad_templates() -> &'static [gst::PadTemplate] {
         static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let formats = gst::List::new(frame::SUPPORTED_FORMATS.iter().map(|f| f.to_str()));
             let caps = gst::Caps::builder("video/x-raw")
-                .field(
-                    "format",
-                    gst::List::new([gst_video::VideoFormat::Rgb.to_str()]),
-                )
+                .field("format", formats.clone())
                 .field("width", gst::IntRange::new(0, i32::MAX))
                 .field("height", gst::IntRange::new(0, i32::MAX))
                 .field(
@@ -407,10 +2260,10 @@ impl ElementImpl for GstFastestDet {
             )
             .unwrap();
 
-            // On the sink pad, we can accept BGR of any
+            // On the sink pad, we can accept the same formats of any
             // width/height and with any framerate
             let caps = gst::Caps::builder("video/x-raw")
-                .field("format", gst_video::VideoFormat::Rgb.to_str())
+                .field("format", formats)
                 .field("width", gst::IntRange::new(0, i32::MAX))
                 .field("height", gst::IntRange::new(0, i32::MAX))
                 .field(
@@ -440,11 +2293,75 @@ impl ElementImpl for GstFastestDet {
             )
             .unwrap();
 
-            vec![src_pad_template, sink_pad_template, src_text_pad_template]
+            // one RGB buffer per target, see `crop_targets`
+            let crop_caps = gst::Caps::builder("video/x-raw")
+                .field("format", gst_video::VideoFormat::Rgb.to_str())
+                .field("width", gst::IntRange::new(1, i32::MAX))
+                .field("height", gst::IntRange::new(1, i32::MAX))
+                .field(
+                    "framerate",
+                    gst::FractionRange::new(
+                        gst::Fraction::new(0, 1),
+                        gst::Fraction::new(i32::MAX, 1),
+                    ),
+                )
+                .build();
+            let src_crop_pad_template = gst::PadTemplate::new(
+                "crop_src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Request,
+                &crop_caps,
+            )
+            .unwrap();
+
+            vec![
+                src_pad_template,
+                sink_pad_template,
+                src_text_pad_template,
+                src_crop_pad_template,
+            ]
         });
 
         PAD_TEMPLATES.as_ref()
     }
+
+    // there's only one `crop_src`, the crops of all the targets go through it
+    fn request_new_pad(
+        &self,
+        templ: &gst::PadTemplate,
+        _name: Option<&str>,
+        _caps: Option<&gst::Caps>,
+    ) -> Option<gst::Pad> {
+        let mut crop_pad = self.crop_pad.lock().unwrap();
+        if crop_pad.is_some() {
+            warning!(CAT, "crop_src is already requested");
+            return None;
+        }
+        let pad = gst::Pad::from_template(templ, Some("crop_src"));
+        {
+            // the new pad starts its own stream
+            let mut state = self.state.lock().unwrap();
+            state.crop_stream_started = false;
+            state.crop_segment_pending = true;
+            state.crop_caps = None;
+        }
+        if self.obj().current_state() > gst::State::Ready {
+            let _ = pad.set_active(true);
+        }
+        self.obj().add_pad(&pad).ok()?;
+        *crop_pad = Some(pad.clone());
+        Some(pad)
+    }
+
+    fn release_pad(&self, pad: &gst::Pad) {
+        let mut crop_pad = self.crop_pad.lock().unwrap();
+        if crop_pad.as_ref() == Some(pad) {
+            *crop_pad = None;
+        }
+        drop(crop_pad);
+        let _ = pad.set_active(false);
+        let _ = self.obj().remove_pad(pad);
+    }
 }
 
 // Implementation of gst_base::BaseTransform virtual methods
@@ -458,6 +2375,90 @@ impl BaseTransformImpl for GstFastestDet {
     // https://gstreamer.freedesktop.org/documentation/base/gstbasetransform.html?gi-language=c#passthrough-mode
     const PASSTHROUGH_ON_SAME_CAPS: bool = false;
     const TRANSFORM_IP_ON_PASSTHROUGH: bool = false;
+
+    fn stop(&self) -> Result<(), gst::ErrorMessage> {
+        self.release_worker();
+        let info = FrameInfo {
+            number: std::mem::take(&mut *self.state.lock().unwrap()).frame_count,
+            ..FrameInfo::default()
+        };
+        let mut settings = self.settings.lock().unwrap();
+        settings.schedule.reset();
+        // a new stream starts with new tracks, the current ones end with this one
+        let events = settings.tracker.clear();
+        let labels = settings.detector().map(|det| det.labels.clone()).unwrap_or_default();
+        let ended: Vec<TargetBox> = events.iter().map(|event| event.target.clone()).collect();
+        let analytics = settings.analytics.as_mut().map(|analytics| {
+            let last = analytics.update(&[], &ended, &labels);
+            analytics.reset();
+            last
+        });
+        drop(settings);
+        self.post_track_events(&events, &labels, &info);
+        if let Some(analytics) = analytics {
+            self.post_analytics(&analytics, &info);
+        }
+        self.parent_stop()
+    }
+
+    // keep `text_pad` and `crop_src` in sync with the video stream
+    fn sink_event(&self, event: gst::Event) -> bool {
+        use gst::EventView;
+        let text_pad = self.text_pad.as_ref().unwrap();
+        let crop_pad = self.crop_pad.lock().unwrap().clone();
+        match event.view() {
+            EventView::Segment(e) => {
+                let mut state = self.state.lock().unwrap();
+                state.segment = Some(e.segment().clone());
+                state.segment_pending = true;
+                state.crop_segment_pending = true;
+            }
+            EventView::FlushStart(_) => {
+                let _ = text_pad.push_event(event.clone());
+                if let Some(ref crop_pad) = crop_pad {
+                    let _ = crop_pad.push_event(event.clone());
+                }
+            }
+            EventView::FlushStop(_) => {
+                let _ = text_pad.push_event(event.clone());
+                if let Some(ref crop_pad) = crop_pad {
+                    let _ = crop_pad.push_event(event.clone());
+                }
+                let mut state = self.state.lock().unwrap();
+                state.segment_pending = true;
+                state.crop_segment_pending = true;
+            }
+            EventView::Eos(_) => {
+                let size = {
+                    let settings = self.settings.lock().unwrap();
+                    (settings.crop_width, settings.crop_height)
+                };
+                let mut state = self.state.lock().unwrap();
+                let text_events = self.text_pad_events(text_pad, &mut state);
+                let crop_events = match crop_pad {
+                    // the caps of the last crop, if any
+                    Some(ref crop_pad) => {
+                        let size = state.crop_caps.unwrap_or(size);
+                        self.crop_pad_events(crop_pad, &mut state, size)
+                    }
+                    None => vec![],
+                };
+                drop(state);
+                for text_event in text_events {
+                    let _ = text_pad.push_event(text_event);
+                }
+                let _ = text_pad.push_event(event.clone());
+                if let Some(ref crop_pad) = crop_pad {
+                    for crop_event in crop_events {
+                        let _ = crop_pad.push_event(crop_event);
+                    }
+                    let _ = crop_pad.push_event(event.clone());
+                }
+            }
+            _ => {}
+        }
+        self.parent_sink_event(event)
+    }
 }
 
 impl VideoFilterImpl for GstFastestDet {
@@ -471,24 +2472,17 @@ impl VideoFilterImpl for GstFastestDet {
         in_frame: &gst_video::VideoFrameRef<&gst::BufferRef>,
         out_frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>,
     ) -> Result<gst::FlowSuccess, gst::FlowError> {
-        // Keep the various metadata we need for working with the video frames in
-        // local variables. This saves some typing below.
-        let cols = in_frame.width();
-        let rows = in_frame.height();
-        let in_data = in_frame.plane_data(0).unwrap();
-        let out_data = out_frame.plane_data_mut(0).unwrap();
-        out_data.copy_from_slice(in_data);
-        self.transform_impl(cols, rows, out_data)
+        // copy all the planes, NV12/I420 have more than one
+        in_frame
+            .copy(out_frame)
+            .map_err(|_| gst::FlowError::Error)?;
+        self.transform_impl(out_frame)
     }
 
     fn transform_frame_ip(
         &self,
         frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>,
     ) -> Result<gst::FlowSuccess, gst::FlowError> {
-        let cols = frame.width();
-        let rows = frame.height();
-        // modify the buffer in place
-        let data = frame.plane_data_mut(0).unwrap();
-        self.transform_impl(cols, rows, data)
+        self.transform_impl(frame)
     }
 }
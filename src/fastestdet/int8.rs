@@ -0,0 +1,600 @@
+// Int8 calibration tables for ncnn2int8, computed with our own preprocessing
+//
+// https://github.com/Tencent/ncnn/blob/master/tools/quantize/ncnn2table.cpp
+use super::common::PixelFormat;
+use super::config::ModelConfig;
+use super::runtime::RuntimeOptions;
+use anyhow::{anyhow, bail, Context, Result};
+use ncnn_rs::Mat;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The layers ncnn2int8 quantizes, which need a weight and an input scale
+const QUANTIZED_LAYERS: [&str; 3] = ["Convolution", "ConvolutionDepthWise", "InnerProduct"];
+
+/// Layers with weights in the `.bin` which `ModelBin` can't skip over
+const UNSUPPORTED_LAYERS: [&str; 14] = [
+    "Bias",
+    "Convolution1D",
+    "Convolution3D",
+    "ConvolutionDepthWise1D",
+    "ConvolutionDepthWise3D",
+    "Deconvolution1D",
+    "Deconvolution3D",
+    "Embed",
+    "GroupNorm",
+    "InstanceNorm",
+    "LayerNorm",
+    "MemoryData",
+    "MultiHeadAttention",
+    "Normalize",
+];
+
+const HISTOGRAM_BINS: usize = 2048;
+/// the positive levels of int8
+const TARGET_BINS: usize = 128;
+/// added to every bin of the distributions compared, so none is empty
+const KL_EPS: f32 = 1e-4;
+
+/// A layer line of a text `.param` file
+pub struct Layer {
+    pub kind: String,
+    pub name: String,
+    pub bottoms: Vec<String>,
+    pub tops: Vec<String>,
+    /// `id=value`, arrays (`-233xx=n,...`) are kept as written under their id
+    params: HashMap<i32, String>,
+}
+
+impl Layer {
+    fn int(&self, id: i32, default: i64) -> Result<i64> {
+        match self.params.get(&id) {
+            Some(value) => value.parse().with_context(|| {
+                format!(
+                    "param {} of layer `{}` isn't an integer: `{}`",
+                    id, self.name, value
+                )
+            }),
+            None => Ok(default),
+        }
+    }
+}
+
+pub struct ParamFile {
+    pub layers: Vec<Layer>,
+}
+
+impl ParamFile {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read param file `{}`", path.display()))?;
+        Self::parse(&text).with_context(|| format!("malformed param file `{}`", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+        if lines.next().map(str::trim) != Some("7767517") {
+            bail!("missing the magic number, only text param files are supported");
+        }
+        let layer_count: usize = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .ok_or_else(|| anyhow!("missing the layer count"))?
+            .parse()?;
+        let mut layers = Vec::with_capacity(layer_count);
+        for line in lines {
+            let mut fields = line.split_whitespace();
+            let mut next = |what: &str| {
+                fields
+                    .next()
+                    .ok_or_else(|| anyhow!("missing the {} in `{}`", what, line))
+            };
+            let kind = next("type")?.to_string();
+            let name = next("name")?.to_string();
+            let bottom_count: usize = next("bottom count")?.parse()?;
+            let top_count: usize = next("top count")?.parse()?;
+            let bottoms = (0..bottom_count)
+                .map(|_| next("bottom").map(str::to_string))
+                .collect::<Result<Vec<_>>>()?;
+            let tops = (0..top_count)
+                .map(|_| next("top").map(str::to_string))
+                .collect::<Result<Vec<_>>>()?;
+            let mut params = HashMap::new();
+            for field in fields {
+                let (id, value) = field
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("expected `id=value`, got `{}`", field))?;
+                let id: i32 = id.parse()?;
+                let id = if id <= -23300 { -id - 23300 } else { id };
+                params.insert(id, value.to_string());
+            }
+            layers.push(Layer {
+                kind,
+                name,
+                bottoms,
+                tops,
+                params,
+            });
+        }
+        if layers.len() != layer_count {
+            bail!("expected {} layers, got {}", layer_count, layers.len());
+        }
+        Ok(ParamFile { layers })
+    }
+
+    /// Whether ncnn2int8 has been run on the model
+    pub fn is_quantized(&self) -> bool {
+        self.layers.iter().any(|layer| {
+            QUANTIZED_LAYERS.contains(&layer.kind.as_str()) && layer.int(8, 0).unwrap_or(0) != 0
+        })
+    }
+}
+
+/// `value` of an IEEE half
+fn f16_to_f32(value: u16) -> f32 {
+    let sign = ((value >> 15) as u32) << 31;
+    let exponent = ((value >> 10) & 0x1f) as u32;
+    let mantissa = (value & 0x3ff) as u32;
+    match exponent {
+        // zero and subnormals
+        0 => {
+            let magnitude = mantissa as f32 * 2f32.powi(-24);
+            if sign != 0 {
+                -magnitude
+            } else {
+                magnitude
+            }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+    }
+}
+
+/// Reads a `.bin` the way `ncnn::ModelBinFromDataReader` does
+struct ModelBin<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ModelBin<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        // everything is aligned on 4 bytes
+        let padded = len.div_ceil(4) * 4;
+        if self.offset + padded > self.data.len() {
+            bail!(
+                "the model file ends at {} bytes, too short for the param file",
+                self.data.len()
+            );
+        }
+        let bytes = &self.data[self.offset..self.offset + len];
+        self.offset += padded;
+        Ok(bytes)
+    }
+
+    fn floats(&mut self, len: usize) -> Result<Vec<f32>> {
+        let bytes = self.take(len * 4)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+
+    /// `load(len, 1)`, plain floats
+    fn load_raw(&mut self, len: usize) -> Result<Vec<f32>> {
+        self.floats(len)
+    }
+
+    /// `load(len, 0)`, a tag telling how the weights are stored then the weights
+    fn load_tagged(&mut self, len: usize) -> Result<Vec<f32>> {
+        let tag = self.take(4)?;
+        match u32::from_le_bytes([tag[0], tag[1], tag[2], tag[3]]) {
+            0x01306b47 => Ok(self
+                .take(len * 2)?
+                .chunks_exact(2)
+                .map(|b| f16_to_f32(u16::from_le_bytes([b[0], b[1]])))
+                .collect()),
+            0x000d4b38 => bail!("the model is already int8 quantized"),
+            0x0002c056 | 0 => self.floats(len),
+            // a table of 256 values indexed by bytes
+            _ => {
+                let table = self.floats(256)?;
+                Ok(self.take(len)?.iter().map(|i| table[*i as usize]).collect())
+            }
+        }
+    }
+}
+
+/// The int8 scale of each output channel of the quantized layers, as computed
+/// by ncnn2table
+fn weight_scales(param: &ParamFile, data: &[u8]) -> Result<Vec<(String, Vec<f32>)>> {
+    let mut bin = ModelBin { data, offset: 0 };
+    let mut scales = vec![];
+    for layer in param.layers.iter() {
+        if UNSUPPORTED_LAYERS.contains(&layer.kind.as_str()) {
+            bail!("can't read the weights of `{}` layers", layer.kind);
+        }
+        // (weights, channels the weights are split in)
+        let weights = match layer.kind.as_str() {
+            "Convolution" | "ConvolutionDepthWise" | "Deconvolution" | "DeconvolutionDepthWise" => {
+                let num_output = layer.int(0, 0)? as usize;
+                let weights = bin.load_tagged(layer.int(6, 0)? as usize)?;
+                if layer.int(5, 0)? != 0 {
+                    bin.load_raw(num_output)?;
+                }
+                let channels = match layer.kind.as_str() {
+                    "ConvolutionDepthWise" => layer.int(7, 1)? as usize,
+                    _ => num_output,
+                };
+                Some((weights, channels))
+            }
+            "InnerProduct" => {
+                let num_output = layer.int(0, 0)? as usize;
+                let weights = bin.load_tagged(layer.int(2, 0)? as usize)?;
+                if layer.int(1, 0)? != 0 {
+                    bin.load_raw(num_output)?;
+                }
+                Some((weights, num_output))
+            }
+            "BatchNorm" => {
+                // slope, mean, variance and bias
+                bin.load_raw(4 * layer.int(0, 0)? as usize)?;
+                None
+            }
+            "Scale" => {
+                let size = layer.int(0, 0)?;
+                // -233 means the scale is the second input
+                if size != -233 {
+                    bin.load_raw(size as usize)?;
+                }
+                if layer.int(1, 0)? != 0 {
+                    bin.load_raw(size.max(0) as usize)?;
+                }
+                None
+            }
+            "PReLU" => {
+                bin.load_raw(layer.int(0, 0)? as usize)?;
+                None
+            }
+            _ => None,
+        };
+        let (weights, channels) = match weights {
+            Some(weights) if QUANTIZED_LAYERS.contains(&layer.kind.as_str()) => weights,
+            _ => continue,
+        };
+        if channels == 0 || weights.len() % channels != 0 {
+            bail!(
+                "layer `{}` has {} weights, which can't be split in {} channels",
+                layer.name,
+                weights.len(),
+                channels
+            );
+        }
+        let layer_scales = weights
+            .chunks_exact(weights.len() / channels)
+            .map(|channel| {
+                let absmax = channel.iter().fold(0f32, |max, w| max.max(w.abs()));
+                if absmax == 0.0 {
+                    1.0
+                } else {
+                    127.0 / absmax
+                }
+            })
+            .collect();
+        scales.push((layer.name.clone(), layer_scales));
+    }
+    if bin.offset != data.len() {
+        bail!(
+            "{} bytes of the model file are left once the weights are read",
+            data.len() - bin.offset
+        );
+    }
+    Ok(scales)
+}
+
+/// Like ncnn2table, the distributions aren't normalized once `KL_EPS` is added
+fn kl_divergence(p: &[f32], q: &[f32]) -> f32 {
+    p.iter().zip(q.iter()).map(|(p, q)| p * (p / q).ln()).sum()
+}
+
+/// The bins covered by `level` when `threshold` bins are squeezed into the
+/// int8 levels, with the part of each bin inside the level
+///
+/// A level spans `threshold / 128` bins, so it starts and ends in the middle
+/// of a bin unless the threshold is a multiple of 128.
+fn level_bins(level: usize, threshold: usize) -> Vec<(usize, f32)> {
+    let per_level = threshold as f32 / TARGET_BINS as f32;
+    let (start, end) = if level + 1 == TARGET_BINS {
+        (threshold as f32 - per_level, threshold as f32)
+    } else {
+        (level as f32 * per_level, (level + 1) as f32 * per_level)
+    };
+    let (left_upper, right_lower) = (start.ceil() as usize, end.floor() as usize);
+    let mut bins: Vec<(usize, f32)> = (left_upper..right_lower).map(|bin| (bin, 1.0)).collect();
+    if left_upper as f32 > start {
+        bins.push((left_upper - 1, left_upper as f32 - start));
+    }
+    if end > right_lower as f32 {
+        bins.push((right_lower, end - right_lower as f32));
+    }
+    bins
+}
+
+/// The bin to clip `histogram` at which loses the least information once the
+/// clipped range is squeezed into the 128 levels of int8, as ncnn2table does
+fn kl_threshold(histogram: &[f32]) -> usize {
+    let total: f32 = histogram.iter().sum();
+    let histogram: Vec<f32> = histogram.iter().map(|v| v / total).collect();
+    let mut best = (f32::MAX, TARGET_BINS);
+    for threshold in TARGET_BINS..histogram.len() {
+        // the outliers are clipped into the last bin
+        let mut clipped: Vec<f32> = histogram[..threshold].iter().map(|v| v + KL_EPS).collect();
+        clipped[threshold - 1] += histogram[threshold..].iter().sum::<f32>();
+        // each level is the mean of the unclipped bins it covers, spread back
+        // over them
+        let mut expanded = vec![KL_EPS; threshold];
+        for level in 0..TARGET_BINS {
+            let bins = level_bins(level, threshold);
+            let covered: f32 = bins.iter().map(|(_, part)| part).sum();
+            let value = bins
+                .iter()
+                .map(|(bin, part)| part * histogram[*bin])
+                .sum::<f32>()
+                / covered;
+            for (bin, part) in bins {
+                expanded[bin] += part * value;
+            }
+        }
+        let kl = kl_divergence(&clipped, &expanded);
+        if kl < best.0 {
+            best = (kl, threshold);
+        }
+    }
+    best.1
+}
+
+/// The activations at the input of a quantized layer
+struct BlobStats {
+    layer: String,
+    blob: String,
+    absmax: f32,
+    /// of the absolute values, zeros excluded, over `[0, absmax]`
+    histogram: Vec<f32>,
+}
+
+impl BlobStats {
+    fn scale(&self) -> f32 {
+        if self.absmax == 0.0 || self.histogram.iter().all(|v| *v == 0.0) {
+            return 1.0;
+        }
+        let threshold = kl_threshold(&self.histogram);
+        let bin_width = self.absmax / HISTOGRAM_BINS as f32;
+        127.0 / ((threshold as f32 + 0.5) * bin_width)
+    }
+}
+
+/// What ncnn2int8 reads besides the fp32 model
+pub struct CalibrationTable {
+    /// per output channel, by layer
+    pub weight_scales: Vec<(String, Vec<f32>)>,
+    /// of the input, by layer
+    pub blob_scales: Vec<(String, f32)>,
+}
+
+impl CalibrationTable {
+    /// The format of ncnn2table, `%f` and a space after every value
+    pub fn write<W: Write>(&self, mut out: W) -> std::io::Result<()> {
+        for (name, scales) in self.weight_scales.iter() {
+            write!(out, "{}_param_0 ", name)?;
+            for scale in scales.iter() {
+                write!(out, "{:.6} ", scale)?;
+            }
+            writeln!(out)?;
+        }
+        for (name, scale) in self.blob_scales.iter() {
+            writeln!(out, "{} {:.6} ", name, scale)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compute the int8 scales of a fp32 model, with the inputs prepared by
+/// `ModelConfig::preprocess` like the detectors do
+///
+/// The activation scales are chosen by KL divergence like ncnn2table. Each
+/// image is read twice, once for the range of the activations and once for
+/// their histograms.
+///
+/// ncnn2table turns fp16 storage and arithmetic off for this, but the net here
+/// still runs with ncnn's defaults: the bindings can't set these options yet,
+/// see `RuntimeOptions`. The activations may differ slightly from fp32.
+pub fn calibrate<P: AsRef<str>>(
+    param_path: P,
+    model_path: P,
+    config: &ModelConfig,
+    runtime: &RuntimeOptions,
+    images: &[PathBuf],
+) -> Result<CalibrationTable> {
+    let (param_path, model_path) = (param_path.as_ref(), model_path.as_ref());
+    if images.is_empty() {
+        bail!("no images to calibrate with");
+    }
+    let param = ParamFile::from_file(param_path)?;
+    if param.is_quantized() {
+        bail!(
+            "`{}` is already int8 quantized, calibrate the fp32 model",
+            param_path
+        );
+    }
+    let data = std::fs::read(model_path)
+        .with_context(|| format!("failed to read model file `{}`", model_path))?;
+    let weight_scales = weight_scales(&param, &data)?;
+    let mut net = runtime.net();
+    net.load_param(param_path)
+        .with_context(|| format!("failed to load param file `{}`", param_path))?;
+    net.load_model(model_path)
+        .with_context(|| format!("failed to load model file `{}`", model_path))?;
+    let mut stats: Vec<BlobStats> = param
+        .layers
+        .iter()
+        .filter(|layer| QUANTIZED_LAYERS.contains(&layer.kind.as_str()))
+        .filter_map(|layer| {
+            Some(BlobStats {
+                layer: layer.name.clone(),
+                blob: layer.bottoms.first()?.clone(),
+                absmax: 0.0,
+                histogram: vec![0.0; HISTOGRAM_BINS],
+            })
+        })
+        .collect();
+    for pass in 0..2 {
+        for path in images.iter() {
+            let img = image::open(path)
+                .with_context(|| format!("failed to read image `{}`", path.display()))?
+                .to_rgb8();
+            let input = config.preprocess(&img.as_flat_samples(), PixelFormat::Rgb, None)?;
+            let mut ex = net.create_extractor();
+            ex.input(&config.input_blob, &input)?;
+            for stat in stats.iter_mut() {
+                let mut blob = Mat::new();
+                ex.extract(&stat.blob, &mut blob)?;
+                let values = (0..blob.c()).flat_map(|q| blob.channel_data(q).iter());
+                if pass == 0 {
+                    stat.absmax = values.fold(stat.absmax, |max, v| max.max(v.abs()));
+                } else if stat.absmax > 0.0 {
+                    let bins_per_unit = HISTOGRAM_BINS as f32 / stat.absmax;
+                    for v in values.map(|v| v.abs()).filter(|v| *v > 0.0) {
+                        let bin = ((v * bins_per_unit) as usize).min(HISTOGRAM_BINS - 1);
+                        stat.histogram[bin] += 1.0;
+                    }
+                }
+            }
+        }
+    }
+    let blob_scales = stats
+        .iter()
+        .map(|stat| (stat.layer.clone(), stat.scale()))
+        .collect();
+    Ok(CalibrationTable {
+        weight_scales,
+        blob_scales,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAM: &str = "7767517
+3 3
+Input data 0 1 data 0=2 1=1 2=1
+Convolution conv 1 1 data conv_out 0=2 1=1 5=1 6=4
+InnerProduct fc 1 1 conv_out fc_out 0=1 1=0 2=3
+";
+
+    /// The weights of `PARAM`, the convolution's in fp32 and the inner
+    /// product's in fp16
+    fn model_bin() -> Vec<u8> {
+        let mut data = vec![];
+        data.extend(0u32.to_le_bytes());
+        for w in [0.5f32, -1.0, 0.25, 2.0] {
+            data.extend(w.to_le_bytes());
+        }
+        // the bias
+        for b in [0.1f32, -0.1] {
+            data.extend(b.to_le_bytes());
+        }
+        data.extend(0x01306b47u32.to_le_bytes());
+        for w in [0x3c00u16, 0xc000, 0x3800] {
+            data.extend(w.to_le_bytes());
+        }
+        // padded to 4 bytes
+        data.extend([0, 0]);
+        data
+    }
+
+    #[test]
+    fn half_floats() {
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x3800), 0.5);
+        assert_eq!(f16_to_f32(0x7bff), 65504.0);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x03ff), 1023.0 * 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x0000).to_bits(), 0f32.to_bits());
+        assert_eq!(f16_to_f32(0x8000).to_bits(), (-0f32).to_bits());
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert!(f16_to_f32(0x7e00).is_nan());
+    }
+
+    #[test]
+    fn parse_param() {
+        let param = ParamFile::parse(PARAM).unwrap();
+        assert_eq!(param.layers.len(), 3);
+        assert_eq!(param.layers[1].kind, "Convolution");
+        assert_eq!(param.layers[1].bottoms, vec!["data"]);
+        assert_eq!(param.layers[1].tops, vec!["conv_out"]);
+        assert_eq!(param.layers[1].int(6, 0).unwrap(), 4);
+        assert!(!param.is_quantized());
+        assert!(ParamFile::parse("7767517\n2 2\nInput data 0 1 data\n").is_err());
+    }
+
+    #[test]
+    fn scales_of_the_weights() {
+        let param = ParamFile::parse(PARAM).unwrap();
+        let scales = weight_scales(&param, &model_bin()).unwrap();
+        assert_eq!(
+            scales,
+            vec![
+                ("conv".to_string(), vec![127.0, 63.5]),
+                ("fc".to_string(), vec![63.5]),
+            ]
+        );
+        // the bias is missing
+        let data = model_bin();
+        assert!(weight_scales(&param, &data[..24]).is_err());
+        // or there are more weights than layers
+        let mut data = model_bin();
+        data.extend(0f32.to_le_bytes());
+        assert!(weight_scales(&param, &data).is_err());
+    }
+
+    #[test]
+    fn threshold_of_a_uniform_histogram() {
+        // nothing is lost by clipping where the values end
+        let mut histogram = vec![0.0; HISTOGRAM_BINS];
+        histogram[..256].fill(10.0);
+        assert_eq!(kl_threshold(&histogram), 256);
+        // and a rare outlier is clipped
+        histogram[2000] = 1.0;
+        assert_eq!(kl_threshold(&histogram), 256);
+    }
+
+    #[test]
+    fn levels_split_the_bins() {
+        // whole bins for a multiple of 128
+        assert_eq!(level_bins(1, 256), vec![(2, 1.0), (3, 1.0)]);
+        // otherwise the levels share the bins at their edges
+        assert_eq!(level_bins(0, 192), vec![(0, 1.0), (1, 0.5)]);
+        assert_eq!(level_bins(1, 192), vec![(2, 1.0), (1, 0.5)]);
+        assert_eq!(level_bins(127, 192), vec![(191, 1.0), (190, 0.5)]);
+    }
+
+    #[test]
+    fn table_format() {
+        let param = ParamFile::parse(PARAM).unwrap();
+        let table = CalibrationTable {
+            weight_scales: weight_scales(&param, &model_bin()).unwrap(),
+            blob_scales: vec![("conv".to_string(), 12.25), ("fc".to_string(), 1.0 / 3.0)],
+        };
+        let mut out = vec![];
+        table.write(&mut out).unwrap();
+        // as written by ncnn2table
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "conv_param_0 127.000000 63.500000 \nfc_param_0 63.500000 \nconv 12.250000 \nfc 0.333333 \n"
+        );
+    }
+}
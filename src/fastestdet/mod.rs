@@ -1,10 +1,30 @@
 use gst::glib;
 use gst::prelude::*;
+use serde_derive::Deserialize;
 pub mod imp;
 pub mod fastest_det;
 pub mod yolo_fastest;
+pub mod yolov5;
+pub mod yolov8;
+pub mod nanodet;
 pub mod common;
+pub mod config;
+pub mod int8;
+pub mod runtime;
+pub mod track;
+pub mod analytics;
+pub mod style;
+pub mod classifier;
+pub mod classify;
+pub mod tile;
+mod crop;
+mod frame;
+mod redact;
+mod schedule;
+mod shared;
 mod utils;
+mod watch;
+mod worker;
 
 // https://gitlab.freedesktop.org/gstreamer/gst-plugins-rs/-/blob/main/video/hsv/src/hsvdetector/imp.rs
 // https://gitlab.freedesktop.org/gstreamer/gstreamer-rs/-/blob/main/gstreamer-video/src/auto/video_filter.rs
@@ -16,6 +36,76 @@ mod utils;
 // https://gstreamer.freedesktop.org/documentation/base/gstbasetransform.html?gi-language=c
 // https://gstreamer.freedesktop.org/documentation/video/gstvideofilter.html?gi-language=c
 
+/// Which `ImageModel` implementation the element should load
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstFastestDetBackend")]
+pub enum Backend {
+    #[enum_value(name = "FastestDet", nick = "fastest-det")]
+    FastestDet = 0,
+    #[enum_value(name = "Yolo-FastestV2", nick = "yolo-fastest")]
+    YoloFastest = 1,
+    #[enum_value(name = "YOLOv5", nick = "yolov5")]
+    Yolov5 = 2,
+    #[enum_value(name = "YOLOv8", nick = "yolov8")]
+    Yolov8 = 3,
+    #[enum_value(name = "NanoDet-Plus", nick = "nanodet-plus")]
+    NanodetPlus = 4,
+}
+
+/// Whether the model of the element is usable, see the `status` property
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstFastestDetModelStatus")]
+pub enum ModelStatus {
+    #[enum_value(name = "No model loaded", nick = "unloaded")]
+    Unloaded = 0,
+    #[enum_value(name = "Model loaded", nick = "loaded")]
+    Loaded = 1,
+    #[enum_value(name = "Failed to load the model", nick = "error")]
+    Error = 2,
+}
+
+/// Which frame to drop when the async inference queue is full
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstFastestDetDropPolicy")]
+pub enum DropPolicy {
+    #[enum_value(name = "Drop the oldest queued frame", nick = "drop-oldest")]
+    DropOldest = 0,
+    #[enum_value(name = "Drop the incoming frame", nick = "drop-newest")]
+    DropNewest = 1,
+}
+
+/// How `is-paint` shows a target, see `common::draw_targets`
+///
+/// The style may choose another mode per class with its `modes` table.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum, Deserialize)]
+#[repr(u32)]
+#[enum_type(name = "GstFastestDetPaintMode")]
+#[serde(rename_all = "kebab-case")]
+pub enum PaintMode {
+    #[enum_value(name = "Draw the box and the label", nick = "boxes")]
+    Boxes = 0,
+    #[enum_value(name = "Blur the box", nick = "blur")]
+    Blur = 1,
+    #[enum_value(name = "Pixelate the box", nick = "pixelate")]
+    Pixelate = 2,
+    #[enum_value(name = "Fill the box with its color", nick = "solid-fill")]
+    SolidFill = 3,
+}
+
+/// How the targets of overlapping tiles are merged, see `tile::merge`
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstFastestDetTileMerge")]
+pub enum TileMerge {
+    #[enum_value(name = "Keep the best of the matching boxes", nick = "nms")]
+    Nms = 0,
+    #[enum_value(name = "Merge the matching boxes into the box enclosing them", nick = "fusion")]
+    Fusion = 1,
+}
+
 glib::wrapper! {
     pub struct GstFastestDet(ObjectSubclass<imp::GstFastestDet>) @extends gst_base::BaseTransform, gst::Element, gst::Object;
 }
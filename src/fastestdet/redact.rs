@@ -0,0 +1,111 @@
+// Blur and pixelate the targets in place, for `PaintMode`
+use image::Pixel;
+use imageproc::drawing::Canvas;
+
+/// box blurs in a row, three are close to a gaussian blur
+const BLUR_PASSES: usize = 3;
+
+/// x, y, width and height of a region within the canvas
+pub type Region = (u32, u32, u32, u32);
+
+/// The part of the box from `(x1, y1)` to `(x2, y2)` within `canvas`, if any
+pub fn clip<C: Canvas>(canvas: &C, (x1, y1, x2, y2): (i32, i32, i32, i32)) -> Option<Region> {
+    let (x1, y1) = (x1.max(0) as u32, y1.max(0) as u32);
+    let x2 = x2.clamp(0, canvas.width() as i32) as u32;
+    let y2 = y2.clamp(0, canvas.height() as i32) as u32;
+    (x2 > x1 && y2 > y1).then(|| (x1, y1, x2 - x1, y2 - y1))
+}
+
+/// Blur `region` with a box filter of `radius`, repeated `BLUR_PASSES` times
+pub fn blur<C>(canvas: &mut C, (x0, y0, w, h): Region, radius: u32)
+where
+    C: Canvas,
+    C::Pixel: Pixel<Subpixel = u8>,
+{
+    let n = C::Pixel::CHANNEL_COUNT as usize;
+    let mut samples = Vec::with_capacity((w * h) as usize * n);
+    for y in y0..y0 + h {
+        for x in x0..x0 + w {
+            let pixel = canvas.get_pixel(x, y);
+            samples.extend(pixel.channels().iter().map(|c| *c as f32));
+        }
+    }
+    let size = (w as usize, h as usize);
+    for _ in 0..BLUR_PASSES {
+        box_blur(&mut samples, size, n, radius as usize, true);
+        box_blur(&mut samples, size, n, radius as usize, false);
+    }
+    let mut values = samples.chunks_exact(n);
+    for y in y0..y0 + h {
+        for x in x0..x0 + w {
+            let mut pixel = canvas.get_pixel(x, y);
+            let value = values.next().unwrap();
+            for (channel, v) in pixel.channels_mut().iter_mut().zip(value) {
+                *channel = v.round().clamp(0.0, 255.0) as u8;
+            }
+            canvas.draw_pixel(x, y, pixel);
+        }
+    }
+}
+
+/// Average of the `2 * radius + 1` samples around each one along the rows or
+/// the columns, the samples past the edges repeat the edge
+fn box_blur(samples: &mut [f32], (w, h): (usize, usize), n: usize, radius: usize, rows: bool) {
+    let (len, lines) = if rows { (w, h) } else { (h, w) };
+    let offset = |line: usize, i: usize| {
+        if rows {
+            (line * w + i) * n
+        } else {
+            (i * w + line) * n
+        }
+    };
+    let r = radius as isize;
+    let mut line_samples = vec![0.0; len * n];
+    for line in 0..lines {
+        for i in 0..len {
+            let at = offset(line, i);
+            line_samples[i * n..(i + 1) * n].copy_from_slice(&samples[at..at + n]);
+        }
+        for c in 0..n {
+            let sample = |i: isize| line_samples[i.clamp(0, len as isize - 1) as usize * n + c];
+            let mut sum: f32 = (-r..=r).map(sample).sum();
+            for i in 0..len {
+                samples[offset(line, i) + c] = sum / (2 * r + 1) as f32;
+                sum += sample(i as isize + r + 1) - sample(i as isize - r);
+            }
+        }
+    }
+}
+
+/// Replace `region` by blocks of `block` x `block` pixels of their mean color
+pub fn pixelate<C>(canvas: &mut C, (x0, y0, w, h): Region, block: u32)
+where
+    C: Canvas,
+    C::Pixel: Pixel<Subpixel = u8>,
+{
+    let n = C::Pixel::CHANNEL_COUNT as usize;
+    for by in (y0..y0 + h).step_by(block as usize) {
+        for bx in (x0..x0 + w).step_by(block as usize) {
+            let (bw, bh) = (block.min(x0 + w - bx), block.min(y0 + h - by));
+            let mut sums = vec![0u32; n];
+            for y in by..by + bh {
+                for x in bx..bx + bw {
+                    let pixel = canvas.get_pixel(x, y);
+                    for (sum, channel) in sums.iter_mut().zip(pixel.channels()) {
+                        *sum += *channel as u32;
+                    }
+                }
+            }
+            let count = bw * bh;
+            let mut mean = canvas.get_pixel(bx, by);
+            for (channel, sum) in mean.channels_mut().iter_mut().zip(sums) {
+                *channel = ((sum + count / 2) / count) as u8;
+            }
+            for y in by..by + bh {
+                for x in bx..bx + bw {
+                    canvas.draw_pixel(x, y, mean);
+                }
+            }
+        }
+    }
+}
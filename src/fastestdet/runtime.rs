@@ -0,0 +1,31 @@
+// How ncnn runs the network, independent of the model
+use ncnn_rs::{Net, Option as NcnnOption};
+
+/// The `ncnn::Option`s we let the user tune, the defaults are ncnn's
+///
+/// Only the threads and Vulkan are exposed by the C API that ncnn-rs binds, so
+/// fp16, bf16, int8, light mode and the packed layout stay at ncnn's defaults
+/// (all on but bf16). Tuning them needs `ncnn_option_set_use_fp16_storage`,
+/// `_use_fp16_arithmetic`, `_use_packing_layout`, `_lightmode` and
+/// `_use_int8_inference` shims in `lib/rust-ncnn` first.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RuntimeOptions {
+    /// 0 for ncnn's choice, the number of big cores
+    pub num_threads: u32,
+    /// needs ncnn built with Vulkan
+    pub vulkan: bool,
+}
+
+impl RuntimeOptions {
+    /// An empty `Net` with the options set, ready for `load_param`
+    pub fn net(&self) -> Net {
+        let mut opt = NcnnOption::new();
+        if self.num_threads > 0 {
+            opt.set_num_threads(self.num_threads);
+        }
+        opt.set_vulkan_compute(self.vulkan);
+        let mut net = Net::new();
+        net.set_option(&opt);
+        net
+    }
+}
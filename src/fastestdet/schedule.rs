@@ -0,0 +1,87 @@
+// Decide on which frames the model runs
+use std::time::Duration;
+
+/// Frame/time based inference schedule
+///
+/// A frame is due when at least `interval` frames and `period` nanoseconds (by
+/// PTS) have passed since the last inference. With `adaptive` the interval is
+/// raised to cover the measured inference time, e.g. 3 if the model takes
+/// 100ms on a 30fps stream.
+#[derive(Debug, Clone)]
+pub struct InferenceSchedule {
+    pub interval: u32,
+    /// 0 to disable
+    pub period: u64,
+    pub adaptive: bool,
+    /// `None` until the first inference
+    frames_since: Option<u32>,
+    last_pts: Option<u64>,
+    /// moving average of the inference time in nanoseconds
+    avg_inference: Option<u64>,
+}
+
+impl Default for InferenceSchedule {
+    fn default() -> Self {
+        InferenceSchedule {
+            interval: 1,
+            period: 0,
+            adaptive: false,
+            frames_since: None,
+            last_pts: None,
+            avg_inference: None,
+        }
+    }
+}
+
+impl InferenceSchedule {
+    /// forget the last inference, keeps the configuration
+    pub fn reset(&mut self) {
+        self.frames_since = None;
+        self.last_pts = None;
+        self.avg_inference = None;
+    }
+
+    /// `interval` raised by the measured inference time if `adaptive`
+    pub fn effective_interval(&self, frame_duration: Option<u64>) -> u32 {
+        let interval = self.interval.max(1);
+        match (self.adaptive, self.avg_inference, frame_duration) {
+            (true, Some(avg), Some(duration)) if duration > 0 => {
+                let needed = avg.div_ceil(duration);
+                interval.max(needed.min(u32::MAX as u64) as u32)
+            }
+            _ => interval,
+        }
+    }
+
+    pub fn is_due(&self, pts: Option<u64>, frame_duration: Option<u64>) -> bool {
+        let frames_due = match self.frames_since {
+            Some(n) => n + 1 >= self.effective_interval(frame_duration),
+            None => true,
+        };
+        let time_due = match (self.period, self.last_pts, pts) {
+            (0, _, _) => true,
+            // a seek backwards also restarts the period
+            (period, Some(last), Some(now)) => now < last || now - last >= period,
+            _ => true,
+        };
+        frames_due && time_due
+    }
+
+    /// record whether the model ran on the current frame
+    pub fn mark(&mut self, ran: bool, pts: Option<u64>) {
+        if ran {
+            self.frames_since = Some(0);
+            self.last_pts = pts;
+        } else if let Some(ref mut n) = self.frames_since {
+            *n = n.saturating_add(1);
+        }
+    }
+
+    pub fn record_inference(&mut self, elapsed: Duration) {
+        let elapsed = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.avg_inference = Some(match self.avg_inference {
+            Some(avg) => (avg * 4 + elapsed) / 5,
+            None => elapsed,
+        });
+    }
+}
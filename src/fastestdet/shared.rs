@@ -0,0 +1,49 @@
+// Values shared between the element instances of the process, looked up by id
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+/// The value of an id, locked while it is being made
+type Slot<T> = Arc<Mutex<Weak<T>>>;
+
+/// Keeps a weak reference to each value, so a value is dropped with the last
+/// element using it and made again by the next one asking for it
+pub struct Registry<T> {
+    entries: Mutex<HashMap<String, Slot<T>>>,
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Registry {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T> Registry<T> {
+    /// The live value of `id`, or the one made by `make` which is registered
+    ///
+    /// Only the slot of `id` stays locked while `make` runs, so that concurrent
+    /// callers with the same id wait for the first one instead of making their
+    /// own, while the other ids are made or looked up meanwhile.
+    pub fn get_or_try_insert_with<E, F>(&self, id: &str, make: F) -> Result<Arc<T>, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let slot = {
+            let mut entries = self.entries.lock().unwrap();
+            // a slot being made is locked and in use
+            entries.retain(|_, slot| {
+                Arc::strong_count(slot) > 1
+                    || slot.try_lock().map_or(true, |value| value.strong_count() > 0)
+            });
+            entries.entry(id.to_string()).or_default().clone()
+        };
+        let mut value = slot.lock().unwrap();
+        if let Some(value) = value.upgrade() {
+            return Ok(value);
+        }
+        let made = Arc::new(make()?);
+        *value = Arc::downgrade(&made);
+        Ok(made)
+    }
+}
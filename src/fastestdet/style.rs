@@ -0,0 +1,238 @@
+// How `draw_targets` paints the targets, shared by the element and the CLI
+use super::common::{TargetBox, BOX_COLOR, TEXT_COLOR};
+use super::PaintMode;
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use rusttype::Font;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+
+/// parsed once for all the styles without a `font`
+static DEFAULT_FONT: Lazy<Option<Font<'static>>> =
+    Lazy::new(|| Font::try_from_bytes(include_bytes!("DejaVuSans.ttf")));
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Corners {
+    /// the whole rectangle
+    #[default]
+    Square,
+    /// only the corners, a quarter of the shorter side long
+    Brackets,
+}
+
+/// The style TOML, every key is optional, e.g.
+///
+/// ```toml
+/// # one color for every class, or `palette = true` for a color per class
+/// color = [0, 255, 2]
+/// palette = true
+/// # overrides of the two above, by class name
+/// colors = { person = [255, 64, 64] }
+/// # defaults to black or white on `label_background`
+/// text_color = [242, 255, 128]
+/// thickness = 2
+/// corners = "brackets"
+/// # `{class}`, `{score}` and `{id}`, the `#` track id left out without one
+/// label = "{class} {id} {score}"
+/// label_background = true
+/// # in pixels, or as a fraction of the frame height with `font_scale`
+/// font_size = 24.8
+/// font_scale = 0.04
+/// font = "/usr/share/fonts/truetype/noto/NotoSans-Regular.ttf"
+/// # overrides of the paint mode by class name, and the blur radius and
+/// # pixelate block size, an eighth of the shorter side of the box by default
+/// modes = { person = "blur", "cell phone" = "solid-fill" }
+/// redact_size = 12
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StyleConfig {
+    pub color: [u8; 3],
+    pub palette: bool,
+    pub colors: HashMap<String, [u8; 3]>,
+    pub text_color: Option<[u8; 3]>,
+    pub thickness: u32,
+    pub corners: Corners,
+    pub label: String,
+    pub label_background: bool,
+    pub font_size: f32,
+    pub font_scale: Option<f32>,
+    /// TTF or OTF file, the embedded DejaVu Sans when not given
+    pub font: Option<String>,
+    pub modes: HashMap<String, PaintMode>,
+    pub redact_size: Option<u32>,
+}
+
+impl Default for StyleConfig {
+    fn default() -> Self {
+        StyleConfig {
+            color: BOX_COLOR,
+            palette: false,
+            colors: HashMap::new(),
+            text_color: None,
+            thickness: 1,
+            corners: Corners::Square,
+            label: "{class} {id} {score}".to_string(),
+            label_background: false,
+            font_size: 24.8,
+            font_scale: None,
+            font: None,
+            modes: HashMap::new(),
+            redact_size: None,
+        }
+    }
+}
+
+/// A checked `StyleConfig` with its font loaded
+#[derive(Clone)]
+pub struct PaintStyle {
+    pub config: StyleConfig,
+    /// `None` if the embedded font is broken
+    font: Option<Font<'static>>,
+}
+
+impl Default for PaintStyle {
+    fn default() -> Self {
+        PaintStyle {
+            config: StyleConfig::default(),
+            font: DEFAULT_FONT.clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for PaintStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PaintStyle")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PaintStyle {
+    pub fn new(config: StyleConfig) -> Result<Self> {
+        if config.thickness == 0 {
+            bail!("thickness should be at least 1");
+        }
+        if config.font_size.is_nan() || config.font_size <= 0.0 {
+            bail!("font_size should be positive");
+        }
+        if config.redact_size == Some(0) {
+            bail!("redact_size should be at least 1");
+        }
+        if let Some(scale) = config.font_scale {
+            if scale.is_nan() || scale <= 0.0 || scale > 1.0 {
+                bail!("font_scale should be in (0, 1]");
+            }
+        }
+        let font = match config.font {
+            Some(ref path) => {
+                let data = std::fs::read(path)
+                    .with_context(|| format!("failed to read font `{}`", path))?;
+                let font = Font::try_from_vec(data)
+                    .ok_or_else(|| anyhow::anyhow!("`{}` is not a TTF or OTF font", path))?;
+                Some(font)
+            }
+            None => DEFAULT_FONT.clone(),
+        };
+        Ok(PaintStyle { config, font })
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read style file `{}`", path))?;
+        Self::parse(&text).with_context(|| format!("malformed style file `{}`", path))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Self::new(toml::from_str(text)?)
+    }
+
+    pub fn font(&self) -> Option<&Font<'static>> {
+        self.font.as_ref()
+    }
+
+    /// The box color of a class, by `label` from `colors` or else by its index
+    pub fn color(&self, class: i32, label: &str) -> [u8; 3] {
+        if let Some(color) = self.config.colors.get(label) {
+            return *color;
+        }
+        if self.config.palette {
+            palette(class)
+        } else {
+            self.config.color
+        }
+    }
+
+    /// The mode of a class, by `label` from `modes` or else `default`
+    pub fn mode(&self, label: &str, default: PaintMode) -> PaintMode {
+        self.config.modes.get(label).copied().unwrap_or(default)
+    }
+
+    /// The blur radius or pixelate block size for a box of `width` x `height`
+    pub fn redact_size(&self, width: u32, height: u32) -> u32 {
+        match self.config.redact_size {
+            Some(size) => size,
+            None => (width.min(height) / 8).max(2),
+        }
+    }
+
+    /// The text color over a label background of `background`
+    pub fn text_color(&self, background: [u8; 3]) -> [u8; 3] {
+        match self.config.text_color {
+            Some(color) => color,
+            None if self.config.label_background => {
+                let [r, g, b] = background;
+                let luma = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+                if luma > 128.0 {
+                    [0, 0, 0]
+                } else {
+                    [255, 255, 255]
+                }
+            }
+            None => TEXT_COLOR,
+        }
+    }
+
+    /// The text height in pixels on a frame `frame_height` high
+    pub fn font_height(&self, frame_height: u32) -> f32 {
+        match self.config.font_scale {
+            Some(scale) => (scale * frame_height as f32).max(1.0),
+            None => self.config.font_size,
+        }
+    }
+
+    /// `label` with the placeholders replaced, empty if nothing is left
+    pub fn label(&self, target: &TargetBox, class_name: &str) -> String {
+        let id = match target.track_id {
+            Some(id) => format!("#{}", id),
+            None => String::new(),
+        };
+        let label = self
+            .config
+            .label
+            .replace("{class}", class_name)
+            .replace("{score}", &format!("{:.2}", target.score))
+            .replace("{id}", &id);
+        // the space left by a missing `{id}`
+        label.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// Colors far apart in hue for consecutive classes, by the golden ratio
+fn palette(class: i32) -> [u8; 3] {
+    let hue = (class.max(0) as f32 * 0.618_034).fract() * 6.0;
+    let (s, v) = (0.75, 0.95);
+    let c = v * s;
+    let x = c * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    [r, g, b].map(|channel| ((channel + m) * 255.0).round() as u8)
+}
@@ -0,0 +1,265 @@
+// Tiled inference for the frames much larger than the model input, the small
+// objects would be lost by shrinking the whole frame at once
+use super::common::{nms_handle, ClassThresholds, ImageModel, PixelFormat, TargetBox};
+use super::TileMerge;
+use anyhow::{bail, Result};
+use image::flat::{FlatSamples, SampleLayout};
+
+/// x, y, width and height of a tile in the frame
+pub type Region = (u32, u32, u32, u32);
+
+/// How the frames are split, see `detect_tiled`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileConfig {
+    /// (width, height) in pixels of the frame
+    pub size: (u32, u32),
+    /// part of a tile shared with the next one, in [0, 1)
+    pub overlap: f32,
+    /// also run on the whole frame, for the objects larger than a tile
+    pub full_frame: bool,
+    pub merge: TileMerge,
+}
+
+impl Default for TileConfig {
+    fn default() -> Self {
+        TileConfig {
+            size: (640, 640),
+            overlap: 0.2,
+            full_frame: true,
+            merge: TileMerge::Nms,
+        }
+    }
+}
+
+impl TileConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.size.0 == 0 || self.size.1 == 0 {
+            bail!("invalid tile size {}x{}", self.size.0, self.size.1);
+        }
+        if self.overlap.is_nan() || self.overlap < 0.0 || self.overlap >= 1.0 {
+            bail!("tile overlap should be in [0, 1)");
+        }
+        Ok(())
+    }
+
+    /// The tiles covering a `width` x `height` frame, row by row
+    ///
+    /// The last tile of a row or column is moved back to end at the edge of the
+    /// frame rather than going past it, replacing the previous one when they
+    /// would nearly be the same, and a frame smaller than a tile is a single
+    /// tile.
+    pub fn tiles(&self, width: u32, height: u32) -> Vec<Region> {
+        let xs = starts(width, self.size.0, self.overlap);
+        let ys = starts(height, self.size.1, self.overlap);
+        let (w, h) = (self.size.0.min(width), self.size.1.min(height));
+        ys.iter()
+            .flat_map(|y| xs.iter().map(move |x| (*x, *y, w, h)))
+            .collect()
+    }
+}
+
+/// Where the tiles start along a side of `len`
+fn starts(len: u32, tile: u32, overlap: f32) -> Vec<u32> {
+    if len <= tile {
+        return vec![0];
+    }
+    let step = ((tile as f32 * (1.0 - overlap)).round() as u32).max(1);
+    let last = len - tile;
+    let mut starts: Vec<u32> = (0..last).step_by(step as usize).collect();
+    // rather than another pass a few pixels after the previous tile, that one
+    // is moved to the edge, as long as the tile before still reaches it
+    let previous = *starts.last().unwrap();
+    if starts.len() > 1 && last - previous <= (step / 8).min(tile - step) {
+        starts.pop();
+    }
+    starts.push(last);
+    starts
+}
+
+/// The `region` of `img`, sharing its samples
+fn sub_samples<'a>(img: &FlatSamples<&'a [u8]>, (x, y, w, h): Region) -> FlatSamples<&'a [u8]> {
+    let layout = img.layout;
+    let start = y as usize * layout.height_stride + x as usize * layout.width_stride;
+    FlatSamples {
+        samples: &img.samples[start..],
+        layout: SampleLayout {
+            width: w,
+            height: h,
+            ..layout
+        },
+        color_hint: img.color_hint,
+    }
+}
+
+/// Detect on every tile of `img`, and on the whole of it with `full_frame`,
+/// then merge the targets found by different passes
+///
+/// Within a pass the targets go through the usual per class thresholds and
+/// NMS. Across the passes, an object cut by a tile border is only partly in
+/// its box, so the boxes are matched by their intersection over the smaller
+/// one rather than IoU, with `nms` as threshold too.
+pub fn detect_tiled(
+    det: &mut dyn ImageModel,
+    img: &FlatSamples<&[u8]>,
+    format: PixelFormat,
+    config: &TileConfig,
+    class_thresholds: &ClassThresholds,
+    (score, nms): (f32, f32),
+) -> Result<Vec<TargetBox>> {
+    let (_, width, height) = img.bounds();
+    let mut regions = config.tiles(width, height);
+    // a single tile is the whole frame already
+    if config.full_frame && regions.len() > 1 {
+        regions.push((0, 0, width, height));
+    }
+    let thresh = class_thresholds.min(score);
+    let mut passes = Vec::with_capacity(regions.len());
+    for region in regions {
+        let (x, y, w, h) = region;
+        let tile = sub_samples(img, region);
+        let input = det.preprocess(&tile, format)?;
+        let mut targets = det.detect(&input, (w as i32, h as i32), thresh)?;
+        class_thresholds.retain(&mut targets, score);
+        let mut targets = nms_handle(&targets, nms);
+        for target in targets.iter_mut() {
+            target.x1 += x as i32;
+            target.x2 += x as i32;
+            target.y1 += y as i32;
+            target.y2 += y as i32;
+        }
+        passes.push(targets);
+    }
+    Ok(merge(passes, config.merge, nms))
+}
+
+/// Intersection over the smaller box, 0 for empty boxes
+fn ios(a: &TargetBox, b: &TargetBox) -> f32 {
+    let smaller = a.area().min(b.area());
+    if smaller > 0 {
+        a.intersection_area(b) as f32 / smaller as f32
+    } else {
+        0.0
+    }
+}
+
+/// Merge the targets of several passes, the best first
+///
+/// A target matches a kept one of the same class from another pass when their
+/// intersection over the smaller box is above `threshold`. `Nms` drops it,
+/// `Fusion` grows the kept box to enclose it.
+pub fn merge(passes: Vec<Vec<TargetBox>>, mode: TileMerge, threshold: f32) -> Vec<TargetBox> {
+    let mut sorted: Vec<(usize, TargetBox)> = passes
+        .into_iter()
+        .enumerate()
+        .flat_map(|(pass, targets)| targets.into_iter().map(move |t| (pass, t)))
+        .collect();
+    sorted.sort_by(|(_, a), (_, b)| b.score.total_cmp(&a.score));
+    // with the passes they were found by, a fused box has several
+    let mut picked: Vec<(Vec<usize>, TargetBox)> = vec![];
+    for (pass, target) in sorted {
+        let matched = picked.iter_mut().find(|(passes, kept)| {
+            !passes.contains(&pass) && kept.class == target.class && ios(kept, &target) > threshold
+        });
+        match (matched, mode) {
+            (Some((passes, kept)), TileMerge::Fusion) => {
+                kept.x1 = kept.x1.min(target.x1);
+                kept.y1 = kept.y1.min(target.y1);
+                kept.x2 = kept.x2.max(target.x2);
+                kept.y2 = kept.y2.max(target.y2);
+                passes.push(pass);
+            }
+            (Some(_), TileMerge::Nms) => {}
+            (None, _) => picked.push((vec![pass], target)),
+        }
+    }
+    picked.into_iter().map(|(_, target)| target).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(x1: i32, y1: i32, x2: i32, y2: i32, score: f32) -> TargetBox {
+        TargetBox {
+            x1,
+            y1,
+            x2,
+            y2,
+            score,
+            class: 0,
+            track_id: None,
+        }
+    }
+
+    fn corners(target: &TargetBox) -> (i32, i32, i32, i32) {
+        (target.x1, target.y1, target.x2, target.y2)
+    }
+
+    #[test]
+    fn starts_of_a_small_frame() {
+        assert_eq!(starts(300, 640, 0.2), vec![0]);
+        assert_eq!(starts(640, 640, 0.2), vec![0]);
+    }
+
+    #[test]
+    fn starts_without_overlap() {
+        assert_eq!(starts(1920, 640, 0.0), vec![0, 640, 1280]);
+        // the last tile can't be dropped without a gap
+        assert_eq!(starts(1300, 640, 0.0), vec![0, 640, 660]);
+    }
+
+    #[test]
+    fn last_start_at_the_edge() {
+        // step of 512
+        assert_eq!(starts(1800, 640, 0.2), vec![0, 512, 1024, 1160]);
+        let config = TileConfig::default();
+        let tiles = config.tiles(3840, 2160);
+        assert!(tiles
+            .iter()
+            .all(|(x, y, w, h)| x + w <= 3840 && y + h <= 2160));
+        assert!(tiles.iter().any(|(x, _, w, _)| x + w == 3840));
+        assert!(tiles.iter().any(|(_, y, _, h)| y + h == 2160));
+    }
+
+    #[test]
+    fn no_near_duplicate_last_start() {
+        // 1030 would be 6 pixels after 1024
+        assert_eq!(starts(1670, 640, 0.2), vec![0, 512, 1030]);
+        // the only tile before can't move
+        assert_eq!(starts(645, 640, 0.2), vec![0, 5]);
+    }
+
+    #[test]
+    fn same_pass_never_merged() {
+        let pass = vec![target(0, 0, 100, 100, 0.9), target(0, 0, 50, 50, 0.8)];
+        assert_eq!(merge(vec![pass.clone()], TileMerge::Nms, 0.4).len(), 2);
+        assert_eq!(merge(vec![pass], TileMerge::Fusion, 0.4).len(), 2);
+    }
+
+    #[test]
+    fn suppressed_across_passes_by_ios() {
+        // the IoU of the cut box is only 0.5, its IoS 1
+        let passes = vec![
+            vec![target(0, 0, 100, 100, 0.9)],
+            vec![target(0, 0, 50, 100, 0.8)],
+            vec![target(200, 0, 300, 100, 0.7)],
+        ];
+        let merged = merge(passes, TileMerge::Nms, 0.6);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(corners(&merged[0]), (0, 0, 100, 100));
+        assert_eq!(corners(&merged[1]), (200, 0, 300, 100));
+    }
+
+    #[test]
+    fn fusion_encloses_the_matches() {
+        let passes = vec![
+            vec![target(0, 0, 100, 100, 0.9)],
+            vec![target(20, 10, 130, 90, 0.8)],
+            vec![target(-10, 20, 60, 110, 0.7)],
+        ];
+        let merged = merge(passes, TileMerge::Fusion, 0.4);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(corners(&merged[0]), (-10, 0, 130, 110));
+        assert_eq!(merged[0].score, 0.9);
+    }
+}
@@ -0,0 +1,385 @@
+// Give the targets an identity across frames, in the way of SORT
+//
+// https://arxiv.org/abs/1602.00763
+use super::common::TargetBox;
+
+/// DeepSORT's noise, relative to the size of the box
+const STD_POSITION: f32 = 1.0 / 20.0;
+const STD_VELOCITY: f32 = 1.0 / 160.0;
+
+/// Constant velocity Kalman filter of one coordinate
+///
+/// The coordinates of a box are independent in DeepSORT's model, so a filter
+/// per coordinate is the same as one over the whole box.
+#[derive(Debug, Clone)]
+struct Axis {
+    /// position and velocity per frame
+    x: [f32; 2],
+    p: [[f32; 2]; 2],
+}
+
+impl Axis {
+    fn new(position: f32, size: f32) -> Self {
+        let pos = 2.0 * STD_POSITION * size;
+        let vel = 10.0 * STD_VELOCITY * size;
+        Axis {
+            x: [position, 0.0],
+            p: [[pos * pos, 0.0], [0.0, vel * vel]],
+        }
+    }
+
+    fn predict(&mut self, size: f32) {
+        let q_pos = (STD_POSITION * size).powi(2);
+        let q_vel = (STD_VELOCITY * size).powi(2);
+        let [[p00, p01], [_, p11]] = self.p;
+        self.x[0] += self.x[1];
+        self.p = [
+            [p00 + 2.0 * p01 + p11 + q_pos, p01 + p11],
+            [p01 + p11, p11 + q_vel],
+        ];
+    }
+
+    fn update(&mut self, position: f32, size: f32) {
+        let r = (STD_POSITION * size).powi(2);
+        let [[p00, p01], [_, p11]] = self.p;
+        let s = p00 + r;
+        let (k0, k1) = (p00 / s, p01 / s);
+        let innovation = position - self.x[0];
+        self.x[0] += k0 * innovation;
+        self.x[1] += k1 * innovation;
+        self.p = [
+            [(1.0 - k0) * p00, (1.0 - k0) * p01],
+            [(1.0 - k0) * p01, p11 - k1 * p01],
+        ];
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Track {
+    id: u64,
+    class: i32,
+    score: f32,
+    /// center x, center y, width and height
+    axes: [Axis; 4],
+    /// matched detections
+    hits: u32,
+    /// updates in a row without a match
+    misses: u32,
+    /// seen `min_hits` times, reported from then on
+    confirmed: bool,
+}
+
+impl Track {
+    fn new(id: u64, target: &TargetBox) -> Self {
+        let [cx, cy, w, h] = center_size(target);
+        Track {
+            id,
+            class: target.class,
+            score: target.score,
+            axes: [
+                Axis::new(cx, w),
+                Axis::new(cy, h),
+                Axis::new(w, w),
+                Axis::new(h, h),
+            ],
+            hits: 1,
+            misses: 0,
+            confirmed: false,
+        }
+    }
+
+    fn size(&self) -> (f32, f32) {
+        (self.axes[2].x[0].max(1.0), self.axes[3].x[0].max(1.0))
+    }
+
+    fn predict(&mut self) {
+        let (w, h) = self.size();
+        for (axis, size) in self.axes.iter_mut().zip([w, h, w, h]) {
+            axis.predict(size);
+        }
+    }
+
+    fn update(&mut self, target: &TargetBox) {
+        let (w, h) = self.size();
+        for ((axis, size), value) in self
+            .axes
+            .iter_mut()
+            .zip([w, h, w, h])
+            .zip(center_size(target))
+        {
+            axis.update(value, size);
+        }
+        self.score = target.score;
+        self.hits += 1;
+        self.misses = 0;
+    }
+
+    /// the estimated box
+    fn target(&self) -> TargetBox {
+        let [cx, cy, w, h] = [0, 1, 2, 3].map(|i| self.axes[i].x[0]);
+        let (w, h) = (w.max(1.0), h.max(1.0));
+        TargetBox {
+            x1: (cx - w / 2.0).round() as i32,
+            y1: (cy - h / 2.0).round() as i32,
+            x2: (cx + w / 2.0).round() as i32,
+            y2: (cy + h / 2.0).round() as i32,
+            score: self.score,
+            class: self.class,
+            track_id: Some(self.id),
+        }
+    }
+}
+
+fn center_size(target: &TargetBox) -> [f32; 4] {
+    let (w, h) = (target.width() as f32, target.height() as f32);
+    [target.x1 as f32 + w / 2.0, target.y1 as f32 + h / 2.0, w, h]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackEventKind {
+    /// the track has been confirmed
+    Start,
+    /// the track has been lost for too long
+    End,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackEvent {
+    pub kind: TrackEventKind,
+    /// the last estimate of the track, `track_id` is always set
+    pub target: TargetBox,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackerConfig {
+    /// below it a detection can't continue a track
+    pub iou_threshold: f32,
+    /// detections before a track is reported
+    pub min_hits: u32,
+    /// updates a track may miss before it ends
+    pub max_misses: u32,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        TrackerConfig {
+            iou_threshold: 0.3,
+            min_hits: 3,
+            max_misses: 3,
+        }
+    }
+}
+
+/// Tracks the targets of a stream
+///
+/// `predict` moves the tracks one frame ahead and should be called on every
+/// frame, `update` matches the detections of a frame, which may be only one
+/// frame in a few. A track only misses when it isn't matched by an update,
+/// so tracks coast through the frames without inference.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    pub config: TrackerConfig,
+    tracks: Vec<Track>,
+    next_id: u64,
+}
+
+impl Tracker {
+    pub fn predict(&mut self) {
+        for track in self.tracks.iter_mut() {
+            track.predict();
+        }
+    }
+
+    /// Match `detections` to the tracks, greedily by IoU within a class, and
+    /// set the `track_id` of the ones continuing a confirmed track
+    pub fn update(&mut self, detections: &mut [TargetBox]) -> Vec<TrackEvent> {
+        let estimates: Vec<TargetBox> = self.tracks.iter().map(Track::target).collect();
+        let mut pairs = vec![];
+        for (t, estimate) in estimates.iter().enumerate() {
+            for (d, detection) in detections.iter().enumerate() {
+                if estimate.class != detection.class {
+                    continue;
+                }
+                let iou = estimate.iou(detection);
+                if iou >= self.config.iou_threshold {
+                    pairs.push((iou, t, d));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let mut track_matched = vec![false; self.tracks.len()];
+        let mut detection_matched = vec![false; detections.len()];
+        let mut events = vec![];
+        for (_, t, d) in pairs {
+            if track_matched[t] || detection_matched[d] {
+                continue;
+            }
+            track_matched[t] = true;
+            detection_matched[d] = true;
+            let track = &mut self.tracks[t];
+            track.update(&detections[d]);
+            if !track.confirmed && track.hits >= self.config.min_hits {
+                track.confirmed = true;
+                events.push(TrackEvent {
+                    kind: TrackEventKind::Start,
+                    target: track.target(),
+                });
+            }
+            if track.confirmed {
+                detections[d].track_id = Some(track.id);
+            }
+        }
+        for (track, matched) in self.tracks.iter_mut().zip(track_matched) {
+            if !matched {
+                track.misses += 1;
+            }
+        }
+        let max_misses = self.config.max_misses;
+        self.tracks.retain(|track| {
+            let lost = track.misses > max_misses;
+            if lost && track.confirmed {
+                events.push(TrackEvent {
+                    kind: TrackEventKind::End,
+                    target: track.target(),
+                });
+            }
+            !lost
+        });
+        for (detection, matched) in detections.iter_mut().zip(detection_matched) {
+            if matched {
+                continue;
+            }
+            self.next_id += 1;
+            let mut track = Track::new(self.next_id, detection);
+            if self.config.min_hits <= 1 {
+                track.confirmed = true;
+                detection.track_id = Some(track.id);
+                events.push(TrackEvent {
+                    kind: TrackEventKind::Start,
+                    target: track.target(),
+                });
+            }
+            self.tracks.push(track);
+        }
+        events
+    }
+
+    /// The estimated boxes of the confirmed tracks
+    pub fn targets(&self) -> Vec<TargetBox> {
+        self.tracks
+            .iter()
+            .filter(|track| track.confirmed)
+            .map(Track::target)
+            .collect()
+    }
+
+    /// Forget every track, ending the confirmed ones
+    pub fn clear(&mut self) -> Vec<TrackEvent> {
+        self.tracks
+            .drain(..)
+            .filter(|track| track.confirmed)
+            .map(|track| TrackEvent {
+                kind: TrackEventKind::End,
+                target: track.target(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(x: i32, class: i32) -> TargetBox {
+        TargetBox {
+            x1: x,
+            y1: 100,
+            x2: x + 50,
+            y2: 200,
+            score: 0.9,
+            class,
+            track_id: None,
+        }
+    }
+
+    /// `updates` detections of a box moving right by `speed` each frame
+    fn run(tracker: &mut Tracker, updates: i32, speed: i32) -> Vec<Vec<TargetBox>> {
+        (0..updates)
+            .map(|i| {
+                tracker.predict();
+                let mut detections = vec![target(100 + i * speed, 0)];
+                tracker.update(&mut detections);
+                detections
+            })
+            .collect()
+    }
+
+    #[test]
+    fn confirmed_after_min_hits() {
+        let mut tracker = Tracker::default();
+        let mut events = vec![];
+        for i in 0..3 {
+            tracker.predict();
+            let mut detections = vec![target(100 + i * 4, 0)];
+            events = tracker.update(&mut detections);
+            assert_eq!(detections[0].track_id.is_some(), i == 2);
+            assert_eq!(tracker.targets().len(), (i == 2) as usize);
+        }
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, TrackEventKind::Start);
+    }
+
+    #[test]
+    fn ids_stay_stable() {
+        let mut tracker = Tracker::default();
+        let frames = run(&mut tracker, 10, 4);
+        let ids: Vec<_> = frames[2..].iter().map(|d| d[0].track_id).collect();
+        assert!(ids[0].is_some());
+        assert!(ids.iter().all(|id| *id == ids[0]));
+    }
+
+    #[test]
+    fn coasts_without_inference() {
+        let mut tracker = Tracker::default();
+        run(&mut tracker, 10, 4);
+        let before = tracker.targets()[0].clone();
+        // frames skipped by the schedule only predict
+        for _ in 0..5 {
+            tracker.predict();
+        }
+        let after = tracker.targets();
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].track_id, before.track_id);
+        assert!(after[0].x1 > before.x1);
+    }
+
+    #[test]
+    fn ends_after_max_misses() {
+        let mut tracker = Tracker::default();
+        run(&mut tracker, 3, 0);
+        let id = tracker.targets()[0].track_id;
+        for _ in 0..tracker.config.max_misses {
+            tracker.predict();
+            assert!(tracker.update(&mut []).is_empty());
+        }
+        tracker.predict();
+        let events = tracker.update(&mut []);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, TrackEventKind::End);
+        assert_eq!(events[0].target.track_id, id);
+        assert!(tracker.targets().is_empty());
+    }
+
+    #[test]
+    fn classes_never_match() {
+        let mut tracker = Tracker::default();
+        run(&mut tracker, 3, 0);
+        tracker.predict();
+        let mut detections = vec![target(100, 1)];
+        tracker.update(&mut detections);
+        assert_eq!(detections[0].track_id, None);
+        let targets = tracker.targets();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].class, 0);
+    }
+}
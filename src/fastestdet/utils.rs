@@ -1,4 +1,8 @@
 // we got built-in `tanh` x.tanh()
+use super::config::ModelConfig;
+use anyhow::Context;
+use ncnn_rs::{Allocator as NcnnAllocator, Mat, MatPixelType, Net};
+
 pub trait Sigmoid {
     fn sigmoid(self) -> Self;
 }
@@ -8,3 +12,146 @@ impl Sigmoid for f32 {
         1.0 / (1.0 + (-self).exp())
     }
 }
+
+/// Convert a 4:2:0 frame to packed RGB with BT.601 limited range coefficients
+///
+/// `uv_step` is the distance between two chroma samples in a row, i.e. `1` for
+/// I420 whose U and V are separate planes and `2` for NV12 whose `u` and `v`
+/// are the same interleaved plane offset by one byte.
+pub fn yuv420_to_rgb(
+    (y_plane, y_stride): (&[u8], usize),
+    (u_plane, u_stride): (&[u8], usize),
+    (v_plane, v_stride): (&[u8], usize),
+    uv_step: usize,
+    (width, height): (usize, usize),
+) -> Vec<u8> {
+    let mut rgb = vec![0u8; width * height * 3];
+    let clamp = |v: i32| (v >> 8).clamp(0, 255) as u8;
+    for row in 0..height {
+        let y_row = &y_plane[row * y_stride..];
+        let u_row = &u_plane[(row / 2) * u_stride..];
+        let v_row = &v_plane[(row / 2) * v_stride..];
+        let out_row = &mut rgb[row * width * 3..(row + 1) * width * 3];
+        for col in 0..width {
+            let c = y_row[col] as i32 - 16;
+            let d = u_row[(col / 2) * uv_step] as i32 - 128;
+            let e = v_row[(col / 2) * uv_step] as i32 - 128;
+            let pixel = &mut out_row[col * 3..col * 3 + 3];
+            pixel[0] = clamp(298 * c + 409 * e + 128);
+            pixel[1] = clamp(298 * c - 100 * d - 208 * e + 128);
+            pixel[2] = clamp(298 * c + 516 * d + 128);
+        }
+    }
+    rgb
+}
+
+/// `(x, y, stride)` of every cell of the feature maps, stride by stride and row by row
+///
+/// This is the order anchor-free heads (YOLOv8, NanoDet) concatenate their
+/// predictions in. The maps are rounded up like NanoDet does.
+pub fn grid_points((width, height): (i32, i32), strides: &[i32]) -> Vec<(i32, i32, i32)> {
+    let mut points = vec![];
+    for &stride in strides.iter() {
+        let (cols, rows) = grid_size((width, height), stride);
+        for y in 0..rows {
+            for x in 0..cols {
+                points.push((x, y, stride));
+            }
+        }
+    }
+    points
+}
+
+/// (columns, rows) of the feature map of `stride`
+pub fn grid_size((width, height): (i32, i32), stride: i32) -> (i32, i32) {
+    ((width + stride - 1) / stride, (height + stride - 1) / stride)
+}
+
+/// Softmax over `bins`, then their expectation
+///
+/// The integral of the Distribution Focal Loss (YOLOv8) and General Focal Loss
+/// (NanoDet), giving a distance in units of the stride.
+pub fn distribution_expectation(bins: &[f32]) -> f32 {
+    let max = bins.iter().copied().fold(f32::MIN, f32::max);
+    let (sum, weighted) = bins
+        .iter()
+        .enumerate()
+        .fold((0.0, 0.0), |(sum, weighted), (i, v)| {
+            let e = (v - max).exp();
+            (sum + e, weighted + e * i as f32)
+        });
+    weighted / sum
+}
+
+/// Rows of `row_len` values, one per point of `grid_points`
+///
+/// The points are either all in one 2D output or split by stride over one
+/// output per stride.
+pub fn output_rows(outputs: &[Mat], row_len: i32, points: usize) -> anyhow::Result<Vec<&[f32]>> {
+    let mut rows = Vec::with_capacity(points);
+    for (i, output) in outputs.iter().enumerate() {
+        if output.w() != row_len {
+            anyhow::bail!(
+                "output {} has rows of {} values, expected {}",
+                i,
+                output.w(),
+                row_len
+            );
+        }
+        let data = output.as_slice::<f32>();
+        rows.extend(data.chunks_exact(row_len as usize).take(output.h() as usize));
+    }
+    if rows.len() != points {
+        anyhow::bail!(
+            "outputs have {} rows, expected {} for the input size and strides",
+            rows.len(),
+            points
+        );
+    }
+    Ok(rows)
+}
+
+/// The outputs of `config` for a blank input, to check their shapes once the
+/// model is loaded rather than on the first frame
+pub fn blank_outputs(
+    net: &Net,
+    config: &ModelConfig,
+    alloc: &NcnnAllocator,
+) -> anyhow::Result<Vec<Mat>> {
+    let (width, height) = config.input_size;
+    let blank = vec![0u8; (width * height * 3) as usize];
+    let input = Mat::from_pixels(&blank, MatPixelType::BGR, width, height, Some(alloc))?;
+    let mut ex = net.create_extractor();
+    ex.input(&config.input_blob, &input)
+        .with_context(|| format!("no input blob `{}`", config.input_blob))?;
+    let mut outputs = Vec::with_capacity(config.output_blobs.len());
+    for name in config.output_blobs.iter() {
+        let mut output = Mat::new();
+        ex.extract(name, &mut output)
+            .with_context(|| format!("no output blob `{}`", name))?;
+        outputs.push(output);
+    }
+    Ok(outputs)
+}
+
+/// Check that the outputs of `config` have a row of `row_len` values for each
+/// point of `grid_points`, see `blank_outputs`
+pub fn check_output_rows(
+    net: &Net,
+    config: &ModelConfig,
+    alloc: &NcnnAllocator,
+    row_len: usize,
+) -> anyhow::Result<()> {
+    let outputs = blank_outputs(net, config, alloc)?;
+    let points = grid_points(config.input_size, &config.strides);
+    output_rows(&outputs, row_len as i32, points.len())?;
+    Ok(())
+}
+
+/// index and value of the largest of `values`, `(0, f32::MIN)` if empty
+pub fn argmax(values: &[f32]) -> (usize, f32) {
+    values
+        .iter()
+        .enumerate()
+        .fold((0, f32::MIN), |a, (i, v)| if *v > a.1 { (i, *v) } else { a })
+}
@@ -0,0 +1,94 @@
+// Poll the model files and call back when they change
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// what `FileWatcher` compares between two polls, `None` if the file is missing
+type Snapshot = Vec<Option<(SystemTime, u64)>>;
+
+fn snapshot(paths: &[PathBuf]) -> Snapshot {
+    paths
+        .iter()
+        .map(|p| {
+            let meta = std::fs::metadata(p).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        })
+        .collect()
+}
+
+/// Polls the modification time and size of some files every `interval`
+///
+/// `on_change` is called once the files have changed and then stayed the same
+/// for a whole interval, so that a file still being written is not picked up.
+/// Polling is used instead of inotify to keep the plugin free of platform code.
+pub struct FileWatcher {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FileWatcher {
+    pub fn spawn<P, F>(interval: Duration, paths: P, on_change: F) -> std::io::Result<Self>
+    where
+        P: Fn() -> Vec<PathBuf> + Send + 'static,
+        F: Fn() + Send + 'static,
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let handle = std::thread::Builder::new()
+            .name("fastestdet-watch".to_string())
+            .spawn(move || {
+                let (lock, cond) = &*thread_stop;
+                let mut watched = paths();
+                let mut last = snapshot(&watched);
+                // the snapshot that differs from `last`, waiting to settle
+                let mut pending: Option<Snapshot> = None;
+                loop {
+                    let stopped = lock.lock().unwrap();
+                    let (stopped, _) = cond
+                        .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+                        .unwrap();
+                    if *stopped {
+                        break;
+                    }
+                    drop(stopped);
+                    let current_paths = paths();
+                    if current_paths != watched {
+                        // the paths are changed by the properties, which is not a file change
+                        watched = current_paths;
+                        last = snapshot(&watched);
+                        pending = None;
+                        continue;
+                    }
+                    let current = snapshot(&watched);
+                    if current == last {
+                        pending = None;
+                    } else if pending.as_ref() == Some(&current) {
+                        last = current;
+                        pending = None;
+                        on_change();
+                    } else {
+                        pending = Some(current);
+                    }
+                }
+            })?;
+        Ok(FileWatcher {
+            stop,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        let (lock, cond) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cond.notify_all();
+        if let Some(handle) = self.handle.take() {
+            // `on_change` may hold the last reference of the owner of the watcher
+            if handle.thread().id() != std::thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
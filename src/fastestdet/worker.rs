@@ -0,0 +1,173 @@
+// Run inference on a separate thread so the streaming thread never waits for the model
+use super::DropPolicy;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+struct Stream<J, R> {
+    jobs: VecDeque<J>,
+    results: Vec<R>,
+}
+
+struct Queue<J, R> {
+    streams: BTreeMap<u64, Stream<J, R>>,
+    next_id: u64,
+    /// the stream served last, for round robin
+    last: u64,
+    running: bool,
+}
+
+impl<J, R> Queue<J, R> {
+    /// the oldest job of the first stream after `last` which has one
+    fn pop(&mut self) -> Option<(u64, J)> {
+        let last = self.last;
+        let id = self
+            .streams
+            .range(last + 1..)
+            .chain(self.streams.range(..=last))
+            .find(|(_, stream)| !stream.jobs.is_empty())
+            .map(|(id, _)| *id)?;
+        self.last = id;
+        let job = self.streams.get_mut(&id)?.jobs.pop_front()?;
+        Some((id, job))
+    }
+}
+
+struct Shared<J, R> {
+    queue: Mutex<Queue<J, R>>,
+    cond: Condvar,
+}
+
+/// A single thread processing the jobs in the order they are submitted
+///
+/// Several streams may share the worker, see `register`. Each has its own queue
+/// and results, and the streams with pending jobs are served in turn so a busy
+/// one can't starve the others.
+///
+/// There is one thread per model since the model runs one frame at a time, so
+/// the streams sharing it are served one after another and the parallelism
+/// comes from ncnn's threads inside each run, see the `num-threads` property.
+///
+/// At most `depth` jobs of a stream are waiting at the same time, see `submit`.
+/// With a depth of 1 and `DropPolicy::DropOldest` the worker always runs on the
+/// newest job it has received. The thread is joined when the worker is dropped.
+pub struct InferenceWorker<J, R> {
+    shared: Arc<Shared<J, R>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl<J: Send + 'static, R: Send + 'static> InferenceWorker<J, R> {
+    pub fn spawn<F>(name: &str, mut f: F) -> std::io::Result<Self>
+    where
+        F: FnMut(J) -> R + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                streams: BTreeMap::new(),
+                next_id: 0,
+                last: 0,
+                running: true,
+            }),
+            cond: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        let handle = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || loop {
+                let (id, job) = {
+                    let mut queue = thread_shared.queue.lock().unwrap();
+                    loop {
+                        if !queue.running {
+                            return;
+                        }
+                        if let Some(next) = queue.pop() {
+                            break next;
+                        }
+                        queue = thread_shared.cond.wait(queue).unwrap();
+                    }
+                };
+                let result = f(job);
+                // the stream may have been unregistered meanwhile
+                let mut queue = thread_shared.queue.lock().unwrap();
+                if let Some(stream) = queue.streams.get_mut(&id) {
+                    stream.results.push(result);
+                }
+            })?;
+        Ok(InferenceWorker {
+            shared,
+            handle: Some(handle),
+        })
+    }
+
+    /// Add a stream, whose id is passed to `submit` and `take_results`
+    pub fn register(&self) -> u64 {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.next_id += 1;
+        let id = queue.next_id;
+        queue.streams.insert(
+            id,
+            Stream {
+                jobs: VecDeque::new(),
+                results: vec![],
+            },
+        );
+        id
+    }
+
+    /// Drop the stream with its pending jobs and results
+    pub fn unregister(&self, stream: u64) {
+        self.shared.queue.lock().unwrap().streams.remove(&stream);
+    }
+
+    /// Queue `job`, returns the number of jobs dropped to respect `depth`
+    pub fn submit(&self, stream: u64, job: J, depth: usize, policy: DropPolicy) -> usize {
+        let depth = depth.max(1);
+        let mut queue = self.shared.queue.lock().unwrap();
+        let jobs = match queue.streams.get_mut(&stream) {
+            Some(stream) => &mut stream.jobs,
+            None => return 1,
+        };
+        let mut dropped = 0;
+        match policy {
+            DropPolicy::DropOldest => {
+                while jobs.len() >= depth {
+                    jobs.pop_front();
+                    dropped += 1;
+                }
+                jobs.push_back(job);
+            }
+            DropPolicy::DropNewest => {
+                if jobs.len() >= depth {
+                    dropped += 1;
+                } else {
+                    jobs.push_back(job);
+                }
+            }
+        }
+        self.shared.cond.notify_one();
+        dropped
+    }
+
+    /// results of `stream` finished since the last call, oldest first
+    pub fn take_results(&self, stream: u64) -> Vec<R> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        match queue.streams.get_mut(&stream) {
+            Some(stream) => std::mem::take(&mut stream.results),
+            None => vec![],
+        }
+    }
+}
+
+impl<J, R> Drop for InferenceWorker<J, R> {
+    fn drop(&mut self) {
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.running = false;
+            queue.streams.clear();
+        }
+        self.shared.cond.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -1,20 +1,20 @@
 // adapted from
 // https://github.com/dog-qiuqiu/FastestDet/blob/main/example/ncnn/FastestDet.cpp
-use super::common::{ImageModel, RgbBuffer, TargetBox};
-
-use anyhow::{bail, Result};
+use super::common::{ImageModel, PixelFormat, TargetBox};
+use super::config::ModelConfig;
+use super::runtime::RuntimeOptions;
+use super::utils::blank_outputs;
+use anyhow::{bail, Context, Result};
 use ncnn_rs::{Allocator as NcnnAllocator, Mat, Net};
-use std::ops::{Deref, Not};
+use image::flat::FlatSamples;
+use std::ops::Not;
 
-const NUM_ANCHOR: usize = 3;
-const ANCHOR: [f32; 12] = [
-    12.64, 19.39, 37.88, 51.48, 55.71, 138.31, 126.91, 78.23, 131.57, 214.55, 279.92, 258.87,
-];
 pub struct YoloFastest {
     alloc: NcnnAllocator,
     net: Net,
     classes: Vec<String>,
-    model_size: (i32, i32),
+    /// input size, blob names, normalization and the anchors of each output
+    config: ModelConfig,
 }
 
 // Maybe I should use mutex instead
@@ -23,15 +23,20 @@ unsafe impl Sync for YoloFastest {}
 
 /// See also `getCategory`
 /// return (Category, Index, Score)
-fn category_score<T>(values: &[f32], index: usize, category: &[T]) -> (String, usize, f32)
+fn category_score<T>(
+    values: &[f32],
+    index: usize,
+    num_anchor: usize,
+    category: &[T],
+) -> (String, usize, f32)
 where
     T: AsRef<str>,
 {
     let num_category = category.len();
-    let obj_score = values[4 * NUM_ANCHOR + index];
+    let obj_score = values[4 * num_anchor + index];
     let (idx, score) = (0..num_category)
         .map(|i| {
-            let score = values[4 * NUM_ANCHOR + NUM_ANCHOR + i];
+            let score = values[4 * num_anchor + num_anchor + i];
             let class_score = obj_score * score;
             (i, class_score)
         })
@@ -44,60 +49,41 @@ where
 impl ImageModel for YoloFastest {
     // https://github.com/Tencent/ncnn/blob/bae2ee375fe025776d18a489a92a7f2357af7312/src/c_api.h#L103
     /// I assume you will read it from image crate with
-    fn preprocess<T: Deref<Target = [u8]> + AsRef<[u8]>>(&self, img: &RgbBuffer<T>) -> Result<Mat> {
-        let mean_vals: Vec<f32> = vec![0.0, 0.0, 0.0];
-        let norm_vals: Vec<f32> = vec![1.0 / 255.0, 1.0 / 255.0, 1.0 / 255.0];
-        let img_size = (img.width() as i32, img.height() as i32);
-        // dbg!(img.as_flat_samples().layout);
-        let img_data = img.as_flat_samples().samples;
-        // NOTE: not sure whether it is correct
-        // https://blog.csdn.net/qianqing13579/article/details/45318279
-        let (_, _, height_stride) = img.as_flat_samples().strides_cwh();
-        use ncnn_rs::MatPixelType;
-        let stride = height_stride;
-        let mut input = Mat::from_pixels_resize(
-            img_data,
-            MatPixelType::RGB.convert(&MatPixelType::BGR),
-            img_size,
-            stride as i32,
-            self.model_size,
-            Some(&self.alloc),
-        )?;
-        input.substract_mean_normalize(&mean_vals, &norm_vals);
-        Ok(input)
+    fn preprocess(&self, img: &FlatSamples<&[u8]>, format: PixelFormat) -> Result<Mat> {
+        self.config.preprocess(img, format, Some(&self.alloc))
     }
 
     fn detect(&mut self, input: &Mat, img_size: (i32, i32), thresh: f32) -> Result<Vec<TargetBox>> {
         let mut ex = self.net.create_extractor();
-        // magic string
-        // https://github.com/dog-qiuqiu/FastestDet/blob/50473cd155cb088aa4a99e64ff6a4b3c24fa07e1/example/ncnn/FastestDet.cpp#L142
-        ex.input("input.1", input)?;
-        let mut outputs: [Mat; 2] = [Mat::new(), Mat::new()];
-        ex.extract("794", &mut outputs[0])?;
-        ex.extract("796", &mut outputs[1])?;
+        ex.input(&self.config.input_blob, input)?;
+        let mut outputs: Vec<Mat> = Vec::with_capacity(self.config.output_blobs.len());
+        for name in self.config.output_blobs.iter() {
+            let mut output = Mat::new();
+            ex.extract(name, &mut output)?;
+            outputs.push(output);
+        }
         let mut target_boxes: Vec<TargetBox> = Vec::new();
-        let (input_height, input_width) = self.model_size;
-        let (img_w, img_h) = img_size;
+        let (input_width, input_height) = self.config.input_size;
+        // boxes are decoded on the letterbox canvas, if any, then moved back
+        let ((img_w, img_h), letterbox) = self.config.decode_size(img_size);
         let scale_w = img_w as f32 / input_width as f32;
         let scale_h = img_h as f32 / input_height as f32;
-        for (i, output) in outputs.iter().enumerate() {
-            let out_h = output.c();
-            let out_w = output.h();
+        let num_anchor = self.config.anchors_per_head;
+        let names = &self.config.output_blobs;
+        for (i, (name, output)) in names.iter().zip(outputs.iter()).enumerate() {
+            let (out_h, out_w, stride) = self.head_shape(name, output)?;
             let out_c = output.w();
-            assert!(input_height / out_h == input_width / out_w);
-            let stride = input_height / out_h;
+            let anchors = &self.config.anchors[i * num_anchor * 2..(i + 1) * num_anchor * 2];
             for h in 0..out_h {
                 let mut values: &[f32] = output.channel_data(h);
                 for w in 0..out_w {
-                    for b in 0..NUM_ANCHOR {
-                        let (_, idx, score) = category_score(&values, b, &self.classes);
+                    for b in 0..num_anchor {
+                        let (_, idx, score) = category_score(values, b, num_anchor, &self.classes);
                         if score > thresh {
                             let bcx = (values[b * 4 + 0] * 2.0 - 0.5 + w as f32) * stride as f32;
                             let bcy = (values[b * 4 + 1] * 2.0 - 0.5 + h as f32) * stride as f32;
-                            let bw = (values[b * 4 + 2] * 2.).powi(2)
-                                * ANCHOR[(i * NUM_ANCHOR * 2) + b as usize * 2 + 0];
-                            let bh = (values[b * 4 + 3] * 2.).powi(2)
-                                * ANCHOR[(i * NUM_ANCHOR * 2) + b as usize * 2 + 1];
+                            let bw = (values[b * 4 + 2] * 2.).powi(2) * anchors[b * 2 + 0];
+                            let bh = (values[b * 4 + 3] * 2.).powi(2) * anchors[b * 2 + 1];
                             let x1 = ((bcx - bw * 0.5) * scale_w) as i32;
                             let x2 = ((bcx + bw * 0.5) * scale_w) as i32;
                             let y1 = ((bcy - bh * 0.5) * scale_h) as i32;
@@ -109,6 +95,7 @@ impl ImageModel for YoloFastest {
                                 y2,
                                 class: idx as i32,
                                 score,
+                                track_id: None,
                             };
                             target_boxes.push(target);
                         }
@@ -117,6 +104,9 @@ impl ImageModel for YoloFastest {
                 }
             }
         }
+        if let Some(letterbox) = letterbox {
+            letterbox.unmap(&mut target_boxes);
+        }
         Ok(target_boxes)
     }
 
@@ -126,11 +116,59 @@ impl ImageModel for YoloFastest {
 }
 
 impl YoloFastest {
-    /// model_size: (width, height)
+    /// (rows, columns, stride) of the output `name`
+    ///
+    /// Each cell has 4 box values and an objectness per anchor, then the class
+    /// scores, which should match `anchors_per_head` and the classes.
+    fn head_shape(&self, name: &str, output: &Mat) -> Result<(i32, i32, i32)> {
+        let (input_width, input_height) = self.config.input_size;
+        let (out_h, out_w, out_c) = (output.c(), output.h(), output.w());
+        let num_anchor = self.config.anchors_per_head;
+        let expected = (5 * num_anchor + self.classes.len()) as i32;
+        if out_c != expected {
+            bail!(
+                "output `{}` has {} values per cell, expected {} for {} anchors and {} classes",
+                name,
+                out_c,
+                expected,
+                num_anchor,
+                self.classes.len()
+            );
+        }
+        if out_h <= 0
+            || out_w <= 0
+            || input_width % out_w != 0
+            || input_height % out_h != 0
+            || input_height / out_h != input_width / out_w
+        {
+            bail!(
+                "output `{}` is a {}x{} grid, which doesn't divide the {}x{} input evenly",
+                name,
+                out_w,
+                out_h,
+                input_width,
+                input_height
+            );
+        }
+        Ok((out_h, out_w, input_height / out_h))
+    }
+
+    /// Check the outputs of a blank input against the anchors
+    fn check_outputs(&self) -> Result<()> {
+        let outputs = blank_outputs(&self.net, &self.config, &self.alloc)?;
+        for (name, output) in self.config.output_blobs.iter().zip(outputs.iter()) {
+            self.head_shape(name, output)?;
+        }
+        Ok(())
+    }
+
+    /// `config` should have `anchors_per_head` anchors for each output blob,
+    /// the output shapes are checked once the model is loaded
     pub fn new<P>(
         param_path: P,
         model_path: P,
-        // model_size should be 352*352 for pretrained FastestDet
+        config: ModelConfig,
+        runtime: &RuntimeOptions,
         classes: Vec<String>,
     ) -> Result<Self>
     where
@@ -138,12 +176,18 @@ impl YoloFastest {
     {
         let mut det = YoloFastest {
             alloc: unsafe { NcnnAllocator::new() },
-            net: Net::new(),
+            net: runtime.net(),
             classes,
-            model_size: (352, 352),
+            config,
         };
-        det.net.load_param(param_path.as_ref())?;
-        det.net.load_model(model_path.as_ref())?;
+        det.net
+            .load_param(param_path.as_ref())
+            .with_context(|| format!("failed to load param file `{}`", param_path.as_ref()))?;
+        det.net
+            .load_model(model_path.as_ref())
+            .with_context(|| format!("failed to load model file `{}`", model_path.as_ref()))?;
+        det.check_outputs()
+            .with_context(|| format!("model `{}` doesn't match the config", model_path.as_ref()))?;
         Ok(det)
     }
 }
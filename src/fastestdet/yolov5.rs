@@ -0,0 +1,151 @@
+// adapted from
+// https://github.com/Tencent/ncnn/blob/master/examples/yolov5.cpp
+use super::common::{ImageModel, PixelFormat, TargetBox};
+use super::config::ModelConfig;
+use super::runtime::RuntimeOptions;
+use super::utils::*;
+use anyhow::{bail, Context, Result};
+use image::flat::FlatSamples;
+use ncnn_rs::{Allocator as NcnnAllocator, Mat, Net};
+
+/// Anchor based YOLOv5 head, one output per stride
+///
+/// Each output has a channel per anchor, a row per cell of the feature map and
+/// `5 + classes` values per row: x, y, w, h, objectness and the class scores,
+/// all before sigmoid.
+pub struct Yolov5 {
+    alloc: NcnnAllocator,
+    net: Net,
+    classes: Vec<String>,
+    /// input size, blob names, strides and anchors
+    config: ModelConfig,
+}
+
+// Maybe I should use mutex instead
+unsafe impl Send for Yolov5 {}
+unsafe impl Sync for Yolov5 {}
+
+impl ImageModel for Yolov5 {
+    fn preprocess(&self, img: &FlatSamples<&[u8]>, format: PixelFormat) -> Result<Mat> {
+        self.config.preprocess(img, format, Some(&self.alloc))
+    }
+
+    fn detect(&mut self, input: &Mat, img_size: (i32, i32), thresh: f32) -> Result<Vec<TargetBox>> {
+        let config = &self.config;
+        let mut ex = self.net.create_extractor();
+        ex.input(&config.input_blob, input)?;
+        // boxes are decoded on the letterbox canvas, if any, then moved back
+        let ((img_w, img_h), letterbox) = config.decode_size(img_size);
+        let (input_w, input_h) = config.input_size;
+        let scale_w = img_w as f32 / input_w as f32;
+        let scale_h = img_h as f32 / input_h as f32;
+        let num_anchor = config.anchors_per_head;
+        let mut target_boxes: Vec<TargetBox> = Vec::new();
+        for (i, (name, &stride)) in config
+            .output_blobs
+            .iter()
+            .zip(config.strides.iter())
+            .enumerate()
+        {
+            let mut output = Mat::new();
+            ex.extract(name, &mut output)?;
+            let (cols, rows) = grid_size(config.input_size, stride);
+            let row_len = self.check_shape(name, stride, &output)?;
+            let anchors = &config.anchors[i * num_anchor * 2..(i + 1) * num_anchor * 2];
+            for q in 0..num_anchor {
+                let feat = output.channel_data(q as i32);
+                for (cell, values) in feat.chunks_exact(row_len).enumerate() {
+                    if cell as i32 >= cols * rows {
+                        break;
+                    }
+                    let (x, y) = (cell as i32 % cols, cell as i32 / cols);
+                    let (class, class_score) = argmax(&values[5..]);
+                    let score = values[4].sigmoid() * class_score.sigmoid();
+                    if score <= thresh {
+                        continue;
+                    }
+                    let cx = (values[0].sigmoid() * 2.0 - 0.5 + x as f32) * stride as f32;
+                    let cy = (values[1].sigmoid() * 2.0 - 0.5 + y as f32) * stride as f32;
+                    let bw = (values[2].sigmoid() * 2.0).powi(2) * anchors[q * 2];
+                    let bh = (values[3].sigmoid() * 2.0).powi(2) * anchors[q * 2 + 1];
+                    target_boxes.push(TargetBox {
+                        x1: ((cx - bw * 0.5) * scale_w) as i32,
+                        y1: ((cy - bh * 0.5) * scale_h) as i32,
+                        x2: ((cx + bw * 0.5) * scale_w) as i32,
+                        y2: ((cy + bh * 0.5) * scale_h) as i32,
+                        score,
+                        class: class as i32,
+                        track_id: None,
+                    });
+                }
+            }
+        }
+        if let Some(letterbox) = letterbox {
+            letterbox.unmap(&mut target_boxes);
+        }
+        Ok(target_boxes)
+    }
+
+    fn labels(&self) -> &Vec<String> {
+        &self.classes
+    }
+}
+
+impl Yolov5 {
+    /// The values per row of the output `name` for `stride`, once its shape
+    /// is checked against the anchors and the classes
+    fn check_shape(&self, name: &str, stride: i32, output: &Mat) -> Result<usize> {
+        let (cols, rows) = grid_size(self.config.input_size, stride);
+        let num_anchor = self.config.anchors_per_head as i32;
+        let expected = (5 + self.classes.len() as i32, cols * rows, num_anchor);
+        let shape = (output.w(), output.h(), output.c());
+        if shape != expected {
+            bail!(
+                "output `{}` is {}x{}x{} (w x h x c), expected {}x{}x{} for stride {}",
+                name,
+                shape.0,
+                shape.1,
+                shape.2,
+                expected.0,
+                expected.1,
+                expected.2,
+                stride
+            );
+        }
+        Ok(expected.0 as usize)
+    }
+
+    /// `config` should have one output blob per stride, see
+    /// `ModelSection::resolve`, the output shapes are checked once the model
+    /// is loaded
+    pub fn new<P>(
+        param_path: P,
+        model_path: P,
+        config: ModelConfig,
+        runtime: &RuntimeOptions,
+        classes: Vec<String>,
+    ) -> Result<Self>
+    where
+        P: AsRef<str>,
+    {
+        let mut det = Yolov5 {
+            alloc: unsafe { NcnnAllocator::new() },
+            net: runtime.net(),
+            classes,
+            config,
+        };
+        det.net
+            .load_param(param_path.as_ref())
+            .with_context(|| format!("failed to load param file `{}`", param_path.as_ref()))?;
+        det.net
+            .load_model(model_path.as_ref())
+            .with_context(|| format!("failed to load model file `{}`", model_path.as_ref()))?;
+        let outputs = blank_outputs(&det.net, &det.config, &det.alloc)?;
+        let names = det.config.output_blobs.iter();
+        for ((name, &stride), output) in names.zip(det.config.strides.iter()).zip(outputs.iter()) {
+            det.check_shape(name, stride, output)
+                .with_context(|| format!("model `{}` doesn't match the config", model_path.as_ref()))?;
+        }
+        Ok(det)
+    }
+}
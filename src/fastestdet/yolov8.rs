@@ -0,0 +1,127 @@
+// adapted from
+// https://github.com/FeiGeChuanShu/ncnn-android-yolov8/blob/main/ncnn-android-yolov8/app/src/main/jni/yolo.cpp
+use super::common::{ImageModel, PixelFormat, TargetBox};
+use super::config::ModelConfig;
+use super::runtime::RuntimeOptions;
+use super::utils::*;
+use anyhow::{Context, Result};
+use image::flat::FlatSamples;
+use ncnn_rs::{Allocator as NcnnAllocator, Mat, Net};
+
+/// Anchor free YOLOv8 head with Distribution Focal Loss boxes
+///
+/// Every point of `grid_points` has a row of `4 * reg_max` box logits (left,
+/// top, right, bottom distances) followed by the class logits.
+pub struct Yolov8 {
+    alloc: NcnnAllocator,
+    net: Net,
+    classes: Vec<String>,
+    /// input size, blob names, strides and `reg_max`
+    config: ModelConfig,
+}
+
+// Maybe I should use mutex instead
+unsafe impl Send for Yolov8 {}
+unsafe impl Sync for Yolov8 {}
+
+impl ImageModel for Yolov8 {
+    fn preprocess(&self, img: &FlatSamples<&[u8]>, format: PixelFormat) -> Result<Mat> {
+        self.config.preprocess(img, format, Some(&self.alloc))
+    }
+
+    fn detect(&mut self, input: &Mat, img_size: (i32, i32), thresh: f32) -> Result<Vec<TargetBox>> {
+        let config = &self.config;
+        let mut ex = self.net.create_extractor();
+        ex.input(&config.input_blob, input)?;
+        let mut outputs = Vec::with_capacity(config.output_blobs.len());
+        for name in config.output_blobs.iter() {
+            let mut output = Mat::new();
+            ex.extract(name, &mut output)?;
+            outputs.push(output);
+        }
+        // boxes are decoded on the letterbox canvas, if any, then moved back
+        let ((img_w, img_h), letterbox) = config.decode_size(img_size);
+        let (input_w, input_h) = config.input_size;
+        let scale_w = img_w as f32 / input_w as f32;
+        let scale_h = img_h as f32 / input_h as f32;
+        let reg_max = config.reg_max as usize;
+        let points = grid_points(config.input_size, &config.strides);
+        let rows = output_rows(&outputs, self.row_len() as i32, points.len())?;
+        let mut target_boxes: Vec<TargetBox> = Vec::new();
+        for ((x, y, stride), values) in points.into_iter().zip(rows) {
+            let (class, class_score) = argmax(&values[4 * reg_max..]);
+            let score = class_score.sigmoid();
+            if score <= thresh {
+                continue;
+            }
+            let distance = |side: usize| {
+                distribution_expectation(&values[side * reg_max..(side + 1) * reg_max])
+                    * stride as f32
+            };
+            let cx = (x as f32 + 0.5) * stride as f32;
+            let cy = (y as f32 + 0.5) * stride as f32;
+            target_boxes.push(TargetBox {
+                x1: ((cx - distance(0)) * scale_w) as i32,
+                y1: ((cy - distance(1)) * scale_h) as i32,
+                x2: ((cx + distance(2)) * scale_w) as i32,
+                y2: ((cy + distance(3)) * scale_h) as i32,
+                score,
+                class: class as i32,
+                track_id: None,
+            });
+        }
+        if let Some(letterbox) = letterbox {
+            letterbox.unmap(&mut target_boxes);
+        }
+        Ok(target_boxes)
+    }
+
+    fn labels(&self) -> &Vec<String> {
+        &self.classes
+    }
+}
+
+impl Yolov8 {
+    /// `4 * reg_max` box logits then the classes
+    fn row_len(&self) -> usize {
+        4 * self.config.reg_max as usize + self.classes.len()
+    }
+
+    /// `config` should have one output blob, or one per stride, see
+    /// `ModelSection::resolve`, the output shapes are checked once the model
+    /// is loaded
+    pub fn new<P>(
+        param_path: P,
+        model_path: P,
+        config: ModelConfig,
+        runtime: &RuntimeOptions,
+        classes: Vec<String>,
+    ) -> Result<Self>
+    where
+        P: AsRef<str>,
+    {
+        let mut det = Yolov8 {
+            alloc: unsafe { NcnnAllocator::new() },
+            net: runtime.net(),
+            classes,
+            config,
+        };
+        det.net
+            .load_param(param_path.as_ref())
+            .with_context(|| format!("failed to load param file `{}`", param_path.as_ref()))?;
+        det.net
+            .load_model(model_path.as_ref())
+            .with_context(|| format!("failed to load model file `{}`", model_path.as_ref()))?;
+        check_output_rows(&det.net, &det.config, &det.alloc, det.row_len())
+            .with_context(|| {
+                format!(
+                    "expected rows of 4 * {} bins and {} classes, with `reg-max` {}",
+                    det.config.reg_max,
+                    det.classes.len(),
+                    det.config.reg_max
+                )
+            })
+            .with_context(|| format!("model `{}` doesn't match the config", model_path.as_ref()))?;
+        Ok(det)
+    }
+}
@@ -5,6 +5,7 @@ pub mod fastestdet;
 // and everything else that this plugin might provide (e.g. typefinders or device providers).
 fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
     fastestdet::register(plugin)?;
+    fastestdet::classify::register(plugin)?;
     Ok(())
 }
 